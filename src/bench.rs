@@ -0,0 +1,363 @@
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::bucket::Bucket;
+use crate::db::{Options, DB};
+use crate::error::{Error, Result};
+use crate::transaction::Tx;
+
+const BUCKET_NAME: &[u8] = b"bench";
+
+/// Order in which keys are written or read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyOrder {
+    #[default]
+    Sequential,
+    Random,
+}
+
+/// Settings of a benchmark run.
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Items to write.
+    pub count: usize,
+    /// Items written per transaction, or all of them in one if 0.
+    pub batch_size: usize,
+    /// Size of the keys. Keys shorter than 8 bytes hold the low bytes of the
+    /// key number, so they repeat once the count outgrows them.
+    pub key_size: usize,
+    pub value_size: usize,
+    pub write_order: KeyOrder,
+    pub read_order: KeyOrder,
+    /// Writes every batch into a bucket of its own, nested in the benchmark
+    /// bucket.
+    pub nested: bool,
+    /// Threads reading every item back at the same time, each in its own
+    /// transaction.
+    pub readers: usize,
+    pub no_sync: bool,
+    /// Seed of the generator behind random keys and random reads.
+    pub seed: u64,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        BenchOptions {
+            count: 1000,
+            batch_size: 0,
+            key_size: 8,
+            value_size: 32,
+            write_order: KeyOrder::Sequential,
+            read_order: KeyOrder::Sequential,
+            nested: false,
+            readers: 1,
+            no_sync: false,
+            seed: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchResults {
+    pub write: PhaseResults,
+    pub read: PhaseResults,
+    /// Bytes written to the file by every commit, meta pages included.
+    pub bytes_written: u64,
+    pub file_size: u64,
+}
+
+/// Timings of the write or the read phase.
+#[derive(Debug, Clone, Default)]
+pub struct PhaseResults {
+    pub ops: usize,
+    pub duration: Duration,
+    /// Latency of a single operation: a whole transaction for writes, a get
+    /// or a cursor step for reads.
+    pub latency: Latency,
+}
+
+impl PhaseResults {
+    /// Returns 0 if the phase took no measurable time.
+    pub fn ops_per_sec(&self) -> f64 {
+        if self.duration.is_zero() {
+            return 0.0;
+        }
+        self.ops as f64 / self.duration.as_secs_f64()
+    }
+
+    fn new(ops: usize, duration: Duration, mut samples: Vec<Duration>) -> PhaseResults {
+        samples.sort_unstable();
+        let percentile = |p: usize| {
+            samples
+                .get((samples.len() * p / 100).min(samples.len().saturating_sub(1)))
+                .copied()
+                .unwrap_or_default()
+        };
+        PhaseResults {
+            ops,
+            duration,
+            latency: Latency {
+                p50: percentile(50),
+                p90: percentile(90),
+                p99: percentile(99),
+                max: samples.last().copied().unwrap_or_default(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Latency {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// Writes `options.count` items to a new database at `path`, then reads them
+/// back, and reports how long both took.
+pub fn run<P: AsRef<Path>>(path: P, options: &BenchOptions) -> Result<BenchResults> {
+    let db = DB::open_with_options(
+        path.as_ref(),
+        Options {
+            no_sync: options.no_sync,
+            ..Options::default()
+        },
+    )?;
+    let mut rng = Rng::new(options.seed);
+    let (write, keys) = write(&db, options, &mut rng)?;
+    let read = read(&db, options, &keys, &mut rng)?;
+
+    Ok(BenchResults {
+        write,
+        read,
+        bytes_written: db.stats().tx_stats.write_bytes,
+        file_size: db.file().metadata()?.len(),
+    })
+}
+
+fn batch_size(options: &BenchOptions) -> usize {
+    match options.batch_size {
+        0 => options.count.max(1),
+        size => size,
+    }
+}
+
+/// A key that was written, along with the nested bucket it went into.
+struct Written {
+    bucket: Option<Vec<u8>>,
+    key: Vec<u8>,
+}
+
+fn write(db: &DB, options: &BenchOptions, rng: &mut Rng) -> Result<(PhaseResults, Vec<Written>)> {
+    let value = vec![0u8; options.value_size];
+    let mut keys = Vec::with_capacity(options.count);
+    let mut samples = Vec::new();
+    let start = Instant::now();
+    for batch in 0..options.count.div_ceil(batch_size(options)) {
+        let first = batch * batch_size(options);
+        let last = (first + batch_size(options)).min(options.count);
+        let tx_start = Instant::now();
+        db.update(|tx| {
+            let mut bucket = tx.create_bucket_if_not_exists(BUCKET_NAME)?;
+            let mut name = None;
+            if options.nested {
+                let n = (batch as u64).to_be_bytes().to_vec();
+                bucket = bucket.create_bucket_if_not_exists(&n)?;
+                name = Some(n);
+            }
+            for i in first..last {
+                let key = match options.write_order {
+                    KeyOrder::Sequential => make_key(i as u64, options.key_size),
+                    KeyOrder::Random => make_key(rng.next(), options.key_size),
+                };
+                bucket.put(&key, &value)?;
+                keys.push(Written {
+                    bucket: name.clone(),
+                    key,
+                });
+            }
+            Ok(())
+        })?;
+        samples.push(tx_start.elapsed());
+    }
+    Ok((
+        PhaseResults::new(keys.len(), start.elapsed(), samples),
+        keys,
+    ))
+}
+
+fn read(db: &DB, options: &BenchOptions, keys: &[Written], rng: &mut Rng) -> Result<PhaseResults> {
+    // Every reader gets its own order, drawn up front so that shuffling is not
+    // timed.
+    let orders: Vec<Vec<usize>> = (0..options.readers.max(1))
+        .map(|_| match options.read_order {
+            KeyOrder::Sequential => Vec::new(),
+            KeyOrder::Random => rng.permutation(keys.len()),
+        })
+        .collect();
+
+    let start = Instant::now();
+    let results: Vec<Result<(usize, Vec<Duration>)>> = thread::scope(|scope| {
+        let handles: Vec<_> = orders
+            .iter()
+            .map(|order| {
+                scope.spawn(move || {
+                    db.view(|tx| match options.read_order {
+                        KeyOrder::Sequential => read_sequential(tx),
+                        KeyOrder::Random => read_random(tx, keys, order),
+                    })
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("reader panicked"))
+            .collect()
+    });
+    let duration = start.elapsed();
+
+    let mut ops = 0;
+    let mut samples = Vec::new();
+    for result in results {
+        let (count, reader_samples) = result?;
+        ops += count;
+        samples.extend(reader_samples);
+    }
+    Ok(PhaseResults::new(ops, duration, samples))
+}
+
+fn read_sequential(tx: &Tx) -> Result<(usize, Vec<Duration>)> {
+    let mut samples = Vec::new();
+    let Some(bucket) = tx.bucket(BUCKET_NAME)? else {
+        return Ok((0, samples));
+    };
+    scan(&bucket, &mut samples)?;
+    Ok((samples.len(), samples))
+}
+
+/// Walks the bucket with a cursor, descending into nested buckets, and times
+/// every step that lands on a value.
+fn scan(bucket: &Bucket, samples: &mut Vec<Duration>) -> Result<()> {
+    let mut cursor = bucket.cursor();
+    let mut step = Instant::now();
+    let mut item = cursor.first()?;
+    while let Some((key, value)) = item {
+        if value.is_some() {
+            samples.push(step.elapsed());
+        } else {
+            let child = bucket.bucket(key)?.ok_or(Error::BucketNotFound)?;
+            scan(&child, samples)?;
+        }
+        step = Instant::now();
+        item = cursor.next()?;
+    }
+    Ok(())
+}
+
+fn read_random(tx: &Tx, keys: &[Written], order: &[usize]) -> Result<(usize, Vec<Duration>)> {
+    let top = tx.bucket(BUCKET_NAME)?.ok_or(Error::BucketNotFound)?;
+    let mut samples = Vec::with_capacity(order.len());
+    for &i in order {
+        let written = &keys[i];
+        let start = Instant::now();
+        let bucket = match &written.bucket {
+            Some(name) => top.bucket(name)?.ok_or(Error::BucketNotFound)?,
+            None => top,
+        };
+        bucket.get(&written.key)?;
+        samples.push(start.elapsed());
+    }
+    Ok((samples.len(), samples))
+}
+
+/// Encodes `n` big-endian in a key of `size` bytes, so that keys sort in the
+/// order of their numbers.
+fn make_key(n: u64, size: usize) -> Vec<u8> {
+    let mut key = vec![0u8; size];
+    let bytes = n.to_be_bytes();
+    let len = size.min(bytes.len());
+    key[size - len..].copy_from_slice(&bytes[bytes.len() - len..]);
+    key
+}
+
+/// Xorshift generator, plenty for picking keys.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    /// Returns the numbers below `n` in random order.
+    fn permutation(&mut self, n: usize) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..n).collect();
+        for i in (1..n).rev() {
+            let j = (self.next() % (i as u64 + 1)) as usize;
+            order.swap(i, j);
+        }
+        order
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::bench::{make_key, run, BenchOptions, KeyOrder, PhaseResults};
+
+    #[test]
+    fn test_make_key() {
+        assert_eq!(make_key(0x0102, 4), [0, 0, 1, 2]);
+        assert_eq!(make_key(0x0102, 10), [0, 0, 0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(make_key(0x0102, 1), [2]);
+    }
+
+    #[test]
+    fn test_ops_per_sec() {
+        let mut phase = PhaseResults {
+            ops: 500,
+            ..PhaseResults::default()
+        };
+        assert_eq!(phase.ops_per_sec(), 0.0);
+        phase.duration = Duration::from_millis(250);
+        assert_eq!(phase.ops_per_sec(), 2000.0);
+    }
+
+    #[test]
+    fn test_run() {
+        let dir = tempfile::tempdir().unwrap();
+        for (i, nested) in [false, true].into_iter().enumerate() {
+            for order in [KeyOrder::Sequential, KeyOrder::Random] {
+                let options = BenchOptions {
+                    count: 2000,
+                    batch_size: 300,
+                    write_order: order,
+                    read_order: order,
+                    nested,
+                    readers: 3,
+                    no_sync: true,
+                    ..BenchOptions::default()
+                };
+                let path = dir.path().join(format!("db-{i}-{order:?}"));
+                let results = run(&path, &options).unwrap();
+                assert_eq!(results.write.ops, 2000);
+                assert_eq!(results.read.ops, 3 * 2000);
+                assert!(results.write.latency.p50 <= results.write.latency.p99);
+                assert!(results.read.latency.p99 <= results.read.latency.max);
+                assert!(results.bytes_written >= 7 * 4096);
+                assert!(results.file_size > 0);
+            }
+        }
+    }
+}
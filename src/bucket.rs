@@ -0,0 +1,1056 @@
+use std::collections::HashMap;
+use std::ops::{Bound, RangeBounds};
+
+use crate::cursor::Cursor;
+use crate::error::{Error, Result};
+use crate::iter::{prefix_end, Iter};
+use crate::node::{self, Node, NodeId};
+use crate::page::{
+    Page, PageId, BRANCH_PAGE_ELEMENT_SIZE, BUCKET_LEAF_FLAG, LEAF_PAGE_ELEMENT_SIZE,
+    LEAF_PAGE_FLAG, PAGE_HEADER_SIZE,
+};
+use crate::transaction::{Slice, Tx};
+
+pub(crate) type BucketId = usize;
+
+/// A tree page as seen by a transaction: the mapped page, or the node it has
+/// been materialized into.
+pub(crate) type PageNode<'tx> = (Option<Page<'tx>>, Option<NodeId>);
+
+/// A collection of key/value pairs, possibly holding nested buckets. Nested
+/// buckets are stored in their parent as leaf elements flagged with
+/// `BUCKET_LEAF_FLAG`, whose value is the child's `BucketHeader`. Small
+/// buckets have a root of 0 and keep their single leaf page inline, right
+/// after the header.
+#[derive(Clone, Copy)]
+pub struct Bucket<'tx> {
+    tx: &'tx Tx<'tx>,
+    id: BucketId,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BucketHeader {
+    pub(crate) root: PageId,
+    pub(crate) sequence: u64,
+}
+
+// root: u64, sequence: u64
+pub(crate) const BUCKET_HEADER_SIZE: usize = 16;
+
+/// How full split nodes are left by default, as a fraction of the page size.
+pub const DEFAULT_FILL_PERCENT: f64 = 0.5;
+pub(crate) const MIN_FILL_PERCENT: f64 = 0.1;
+pub(crate) const MAX_FILL_PERCENT: f64 = 1.0;
+
+/// Largest key, or bucket name, that can be stored.
+pub const MAX_KEY_SIZE: usize = 32768;
+/// Largest value that can be stored.
+pub const MAX_VALUE_SIZE: usize = (1 << 31) - 2;
+
+/// Page usage of a bucket and everything nested in it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BucketStats {
+    pub branch_pages: usize,
+    pub branch_overflow: usize,
+    pub leaf_pages: usize,
+    pub leaf_overflow: usize,
+    /// Keys, including the names of nested buckets.
+    pub keys: usize,
+    /// Levels of pages from the root to the deepest leaf, through nested
+    /// buckets.
+    pub depth: usize,
+    /// Bytes taken up by branch pages, and the part of them holding data.
+    pub branch_alloc: usize,
+    pub branch_in_use: usize,
+    /// Bytes taken up by leaf pages, and the part of them holding data.
+    pub leaf_alloc: usize,
+    pub leaf_in_use: usize,
+    /// Buckets, counting the bucket itself.
+    pub buckets: usize,
+    /// Buckets stored inline in their parent, and the bytes they take up.
+    pub inline_buckets: usize,
+    pub inline_bucket_in_use: usize,
+}
+
+impl BucketStats {
+    pub fn add(&mut self, other: &BucketStats) {
+        self.branch_pages += other.branch_pages;
+        self.branch_overflow += other.branch_overflow;
+        self.leaf_pages += other.leaf_pages;
+        self.leaf_overflow += other.leaf_overflow;
+        self.keys += other.keys;
+        self.depth = self.depth.max(other.depth);
+        self.branch_alloc += other.branch_alloc;
+        self.branch_in_use += other.branch_in_use;
+        self.leaf_alloc += other.leaf_alloc;
+        self.leaf_in_use += other.leaf_in_use;
+        self.buckets += other.buckets;
+        self.inline_buckets += other.inline_buckets;
+        self.inline_bucket_in_use += other.inline_bucket_in_use;
+    }
+}
+
+/// Per-transaction state of an opened bucket.
+pub(crate) struct BucketState {
+    pub(crate) header: BucketHeader,
+    pub(crate) inline_page: Option<Slice>,
+    pub(crate) root_node: Option<NodeId>,
+    /// Materialized nodes by the page they were read from.
+    pub(crate) nodes: HashMap<PageId, NodeId>,
+    pub(crate) buckets: HashMap<Vec<u8>, BucketId>,
+    pub(crate) fill_percent: f64,
+}
+
+impl BucketHeader {
+    pub(crate) fn read(buf: &[u8]) -> Option<BucketHeader> {
+        let buf = buf.get(..BUCKET_HEADER_SIZE)?;
+        Some(BucketHeader {
+            root: u64::from_le_bytes(buf[0..8].try_into().unwrap()),
+            sequence: u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+        })
+    }
+
+    pub(crate) fn write(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(BUCKET_HEADER_SIZE);
+        buf.extend_from_slice(&self.root.to_le_bytes());
+        buf.extend_from_slice(&self.sequence.to_le_bytes());
+        buf
+    }
+}
+
+impl BucketState {
+    pub(crate) fn new(header: BucketHeader) -> BucketState {
+        BucketState {
+            header,
+            inline_page: None,
+            root_node: None,
+            nodes: HashMap::new(),
+            buckets: HashMap::new(),
+            fill_percent: DEFAULT_FILL_PERCENT,
+        }
+    }
+}
+
+impl<'tx> Bucket<'tx> {
+    pub(crate) fn new(tx: &'tx Tx<'tx>, id: BucketId) -> Bucket<'tx> {
+        Bucket { tx, id }
+    }
+
+    pub fn tx(&self) -> &'tx Tx<'tx> {
+        self.tx
+    }
+
+    pub fn cursor(&self) -> Cursor<'tx> {
+        Cursor::new(*self)
+    }
+
+    /// Iterates over every item of the bucket in key order.
+    pub fn iter(&self) -> Iter<'tx> {
+        self.range::<(Bound<&[u8]>, Bound<&[u8]>)>((Bound::Unbounded, Bound::Unbounded))
+    }
+
+    /// Iterates over the items whose key falls within `range`.
+    pub fn range<R: RangeBounds<[u8]>>(&self, range: R) -> Iter<'tx> {
+        Iter::new(
+            self.cursor(),
+            self.cursor(),
+            range.start_bound().map(<[u8]>::to_vec),
+            range.end_bound().map(<[u8]>::to_vec),
+        )
+    }
+
+    /// Iterates over the items whose key starts with `prefix`.
+    pub fn prefix(&self, prefix: &[u8]) -> Iter<'tx> {
+        let upper = match prefix_end(prefix) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        Iter::new(
+            self.cursor(),
+            self.cursor(),
+            Bound::Included(prefix.to_vec()),
+            upper,
+        )
+    }
+
+    /// How full nodes are left when they are split, as a fraction of the page
+    /// size.
+    pub fn fill_percent(&self) -> f64 {
+        self.state().fill_percent
+    }
+
+    /// Sets how full nodes are left when they are split, clamped to between
+    /// 0.1 and 1.0, with NaN meaning the default. Buckets only ever appended
+    /// to pack best at 1.0. The setting lasts for the current transaction.
+    pub fn set_fill_percent(&self, fill_percent: f64) {
+        self.state_mut().fill_percent = if fill_percent.is_nan() {
+            DEFAULT_FILL_PERCENT
+        } else {
+            fill_percent.clamp(MIN_FILL_PERCENT, MAX_FILL_PERCENT)
+        };
+    }
+
+    /// Returns the current value of the bucket's sequence.
+    pub fn sequence(&self) -> u64 {
+        self.header().sequence
+    }
+
+    /// Sets the bucket's sequence. Like any other change, it is only kept if
+    /// the transaction commits.
+    pub fn set_sequence(&self, sequence: u64) -> Result<()> {
+        self.check_writable()?;
+        // The header is only written back for buckets with a root node.
+        self.root_node()?;
+        self.state_mut().header.sequence = sequence;
+        Ok(())
+    }
+
+    /// Increments the bucket's sequence and returns the new value, which makes
+    /// it a source of unique ids.
+    pub fn next_sequence(&self) -> Result<u64> {
+        let sequence = self.sequence() + 1;
+        self.set_sequence(sequence)?;
+        Ok(sequence)
+    }
+
+    /// Walks the pages of the bucket and of its nested buckets. Only what was
+    /// last committed is counted, not changes made by this transaction.
+    pub fn stats(&self) -> Result<BucketStats> {
+        bucket_stats(self.tx, self.root_page()?, self.header().root == 0)
+    }
+
+    /// Returns the value stored under `key`. Keys holding a nested bucket have
+    /// no value.
+    pub fn get(&self, key: &[u8]) -> Result<Option<&'tx [u8]>> {
+        match self.lookup(key)? {
+            Some((flags, value)) if flags & BUCKET_LEAF_FLAG as u32 == 0 => {
+                Ok(Some(self.tx.bytes(value)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        check_key(key)?;
+        if value.len() > MAX_VALUE_SIZE {
+            return Err(Error::ValueTooLarge);
+        }
+        if let Some((flags, _)) = self.lookup(key)? {
+            if flags & BUCKET_LEAF_FLAG as u32 != 0 {
+                return Err(Error::IncompatibleValue);
+            }
+        }
+        let key = self.tx.alloc_bytes(key);
+        let value = self.tx.alloc_bytes(value);
+        self.put_inode(key, value, 0)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        match self.lookup(key)? {
+            Some((flags, _)) if flags & BUCKET_LEAF_FLAG as u32 != 0 => {
+                Err(Error::IncompatibleValue)
+            }
+            Some(_) => self.del_inode(key),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the nested bucket called `name`, if it exists.
+    pub fn bucket(&self, name: &[u8]) -> Result<Option<Bucket<'tx>>> {
+        if let Some(&id) = self.state().buckets.get(name) {
+            return Ok(Some(Bucket::new(self.tx, id)));
+        }
+        let value = match self.lookup(name)? {
+            Some((flags, value)) if flags & BUCKET_LEAF_FLAG as u32 != 0 => value,
+            _ => return Ok(None),
+        };
+        let value = self.tx.bytes(value);
+        let header = BucketHeader::read(value).ok_or(Error::Corrupt {
+            page_id: self.header().root,
+            reason: "bucket header out of bounds",
+        })?;
+        let mut state = BucketState::new(header);
+        if header.root == 0 {
+            state.inline_page = Some(Slice::new(&value[BUCKET_HEADER_SIZE..]));
+        }
+        Ok(Some(self.open_child(name, state)))
+    }
+
+    pub fn create_bucket(&self, name: &[u8]) -> Result<Bucket<'tx>> {
+        self.check_writable()?;
+        check_key(name)?;
+        match self.lookup(name)? {
+            Some((flags, _)) if flags & BUCKET_LEAF_FLAG as u32 != 0 => {
+                return Err(Error::BucketExists)
+            }
+            Some(_) => return Err(Error::IncompatibleValue),
+            None => {}
+        }
+
+        // New buckets start out inline with an empty leaf.
+        let header = BucketHeader {
+            root: 0,
+            sequence: 0,
+        };
+        let mut value = header.write();
+        value.resize(BUCKET_HEADER_SIZE + PAGE_HEADER_SIZE, 0);
+        Page::write_header(&mut value[BUCKET_HEADER_SIZE..], 0, LEAF_PAGE_FLAG, 0, 0);
+        let value = self.tx.alloc_bytes(&value);
+
+        let mut state = BucketState::new(header);
+        state.inline_page = Some(Slice::new(&self.tx.bytes(value)[BUCKET_HEADER_SIZE..]));
+        let key = self.tx.alloc_bytes(name);
+        self.put_inode(key, value, BUCKET_LEAF_FLAG as u32)?;
+        Ok(self.open_child(name, state))
+    }
+
+    pub fn create_bucket_if_not_exists(&self, name: &[u8]) -> Result<Bucket<'tx>> {
+        match self.bucket(name)? {
+            Some(bucket) => Ok(bucket),
+            None => self.create_bucket(name),
+        }
+    }
+
+    pub fn delete_bucket(&self, name: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        match self.lookup(name)? {
+            Some((flags, _)) if flags & BUCKET_LEAF_FLAG as u32 != 0 => {}
+            Some(_) => return Err(Error::IncompatibleValue),
+            None => return Err(Error::BucketNotFound),
+        }
+
+        // Nested buckets are deleted first so that their pages are freed too.
+        let child = self.bucket(name)?.ok_or(Error::BucketNotFound)?;
+        let mut names = Vec::new();
+        for item in child.iter() {
+            if let (key, None) = item? {
+                names.push(key);
+            }
+        }
+        for name in names {
+            child.delete_bucket(name)?;
+        }
+
+        self.state_mut().buckets.remove(name);
+        {
+            let mut state = child.state_mut();
+            state.nodes.clear();
+            state.root_node = None;
+        }
+        child.free()?;
+        self.del_inode(name)
+    }
+
+    /// Merges the nodes of this bucket and its open children that deletes left
+    /// underfilled.
+    pub(crate) fn rebalance(&self) -> Result<()> {
+        let mut nodes: Vec<(PageId, NodeId)> = self
+            .state()
+            .nodes
+            .iter()
+            .map(|(&page_id, &id)| (page_id, id))
+            .collect();
+        nodes.sort_unstable();
+        for (page_id, id) in nodes {
+            // Skip nodes merged away while rebalancing an earlier one.
+            if self.state().nodes.get(&page_id) == Some(&id) {
+                node::rebalance(self, id)?;
+            }
+        }
+
+        let children: Vec<BucketId> = self.state().buckets.values().copied().collect();
+        for id in children {
+            Bucket::new(self.tx, id).rebalance()?;
+        }
+        Ok(())
+    }
+
+    /// Writes every modified node of this bucket and its open children to
+    /// freshly allocated pages, updating the headers stored in the parents.
+    pub(crate) fn spill(&self) -> Result<()> {
+        let children: Vec<(Vec<u8>, BucketId)> = self
+            .state()
+            .buckets
+            .iter()
+            .map(|(name, &id)| (name.clone(), id))
+            .collect();
+        for (name, id) in children {
+            let child = Bucket::new(self.tx, id);
+            let value = if child.inlineable() {
+                child.free()?;
+                child.write_inline()
+            } else {
+                child.spill()?;
+                child.header().write()
+            };
+            // Untouched buckets keep the value already stored in this one.
+            if child.state().root_node.is_none() {
+                continue;
+            }
+            let key = self.tx.alloc_bytes(&name);
+            let value = self.tx.alloc_bytes(&value);
+            self.put_inode(key, value, BUCKET_LEAF_FLAG as u32)?;
+        }
+
+        let Some(mut root) = self.state().root_node else {
+            return Ok(());
+        };
+        node::spill(self, root)?;
+
+        // Splitting the root gives it a parent, which becomes the new root.
+        let nodes = self.tx.nodes.borrow();
+        while let Some(parent) = nodes[root].parent {
+            root = parent;
+        }
+        let mut state = self.state_mut();
+        state.root_node = Some(root);
+        state.header.root = nodes[root].page_id;
+        Ok(())
+    }
+
+    /// Adds every page of the bucket to the freelist. Inline buckets have no
+    /// pages of their own.
+    fn free(&self) -> Result<()> {
+        let root = self.header().root;
+        if root == 0 {
+            return Ok(());
+        }
+        self.free_page_node(root)
+    }
+
+    fn free_page_node(&self, page_id: PageId) -> Result<()> {
+        let children = match self.page_node(page_id)? {
+            (_, Some(id)) => {
+                let mut nodes = self.tx.nodes.borrow_mut();
+                node::free(self, &mut nodes[id])?;
+                let node = &nodes[id];
+                if node.is_leaf {
+                    Vec::new()
+                } else {
+                    node.inodes.iter().map(|inode| inode.page_id).collect()
+                }
+            }
+            (Some(page), None) => {
+                self.tx.free(page_id)?;
+                if page.is_leaf() {
+                    Vec::new()
+                } else {
+                    page.branch_page_elements()
+                        .map(|elem| elem.map(|elem| elem.page_id()))
+                        .collect::<std::result::Result<_, _>>()?
+                }
+            }
+            (None, None) => Vec::new(),
+        };
+        for child in children {
+            self.free_page_node(child)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the bucket is small enough to be stored inline in its parent:
+    /// a single leaf without nested buckets, taking at most a quarter page.
+    fn inlineable(&self) -> bool {
+        let Some(root) = self.state().root_node else {
+            return false;
+        };
+        let nodes = self.tx.nodes.borrow();
+        let node = &nodes[root];
+        node.is_leaf
+            && node.size() <= self.tx.page_size() / 4
+            && node
+                .inodes
+                .iter()
+                .all(|inode| inode.flags & BUCKET_LEAF_FLAG as u32 == 0)
+    }
+
+    /// Encodes the bucket as an inline value: a header with a zero root
+    /// followed by its root leaf.
+    fn write_inline(&self) -> Vec<u8> {
+        let root = self.state().root_node.unwrap();
+        let nodes = self.tx.nodes.borrow();
+        let node = &nodes[root];
+
+        let mut state = self.state_mut();
+        state.header.root = 0;
+        let mut value = state.header.write();
+        value.resize(BUCKET_HEADER_SIZE + node.size(), 0);
+        node.write(&mut value[BUCKET_HEADER_SIZE..], 0, 0);
+        value
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if !self.tx.writable() {
+            return Err(Error::TxNotWritable);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn state(&self) -> std::cell::Ref<'_, BucketState> {
+        std::cell::Ref::map(self.tx.buckets.borrow(), |buckets| &buckets[self.id])
+    }
+
+    pub(crate) fn state_mut(&self) -> std::cell::RefMut<'_, BucketState> {
+        std::cell::RefMut::map(self.tx.buckets.borrow_mut(), |buckets| {
+            &mut buckets[self.id]
+        })
+    }
+
+    pub(crate) fn header(&self) -> BucketHeader {
+        self.state().header
+    }
+
+    fn open_child(&self, name: &[u8], state: BucketState) -> Bucket<'tx> {
+        let id = {
+            let mut buckets = self.tx.buckets.borrow_mut();
+            buckets.push(state);
+            buckets.len() - 1
+        };
+        self.state_mut().buckets.insert(name.to_vec(), id);
+        Bucket::new(self.tx, id)
+    }
+
+    /// Finds `key` and returns its flags and value.
+    fn lookup(&self, key: &[u8]) -> Result<Option<(u32, Slice)>> {
+        match self.cursor().seek_raw(key)? {
+            Some((found, value, flags)) if found.as_bytes() == key => Ok(Some((flags, value))),
+            _ => Ok(None),
+        }
+    }
+
+    pub(crate) fn root_elem(&self) -> Result<PageNode<'tx>> {
+        self.page_node(self.header().root)
+    }
+
+    /// Returns the page or the materialized node stored at `page_id`.
+    pub(crate) fn page_node(&self, page_id: PageId) -> Result<PageNode<'tx>> {
+        if let Some(&node) = self.state().nodes.get(&page_id) {
+            return Ok((None, Some(node)));
+        }
+        if page_id == self.header().root {
+            return Ok((Some(self.root_page()?), None));
+        }
+        Ok((Some(self.tx.page(page_id)?), None))
+    }
+
+    fn root_page(&self) -> Result<Page<'tx>> {
+        let state = self.state();
+        match state.inline_page {
+            Some(page) => Ok(Page::from_bytes(self.tx.bytes(page))?),
+            None => Ok(self.tx.page(state.header.root)?),
+        }
+    }
+
+    /// Returns the root node of the bucket, materializing it on first use.
+    pub(crate) fn root_node(&self) -> Result<NodeId> {
+        if let Some(root) = self.state().root_node {
+            return Ok(root);
+        }
+        self.node(self.header().root, None)
+    }
+
+    /// Returns the node for `page_id`, materializing it as a child of `parent`
+    /// on first use.
+    pub(crate) fn node(&self, page_id: PageId, parent: Option<NodeId>) -> Result<NodeId> {
+        if let Some(&id) = self.state().nodes.get(&page_id) {
+            return Ok(id);
+        }
+        let page = if page_id == self.header().root {
+            self.root_page()?
+        } else {
+            self.tx.page(page_id)?
+        };
+        let mut node = Node::read(&page)?;
+        node.parent = parent;
+        let id = self.tx.add_node(node);
+        self.tx.stats.borrow_mut().nodes += 1;
+
+        match parent {
+            Some(parent) => self.tx.nodes.borrow_mut()[parent].children.push(id),
+            None => self.state_mut().root_node = Some(id),
+        }
+        self.state_mut().nodes.insert(page_id, id);
+        Ok(id)
+    }
+
+    fn put_inode(&self, key: Slice, value: Slice, flags: u32) -> Result<()> {
+        let mut cursor = self.cursor();
+        cursor.seek_raw(key.as_bytes())?;
+        let node = cursor.node()?;
+        self.tx.nodes.borrow_mut()[node].put(key.as_bytes(), key, value, 0, flags);
+        Ok(())
+    }
+
+    fn del_inode(&self, key: &[u8]) -> Result<()> {
+        let mut cursor = self.cursor();
+        cursor.seek_raw(key)?;
+        let node = cursor.node()?;
+        self.tx.nodes.borrow_mut()[node].del(key);
+        Ok(())
+    }
+}
+
+fn bucket_stats<'tx>(tx: &'tx Tx, root: Page<'tx>, inline: bool) -> Result<BucketStats> {
+    let mut stats = BucketStats {
+        buckets: 1,
+        inline_buckets: inline as usize,
+        ..BucketStats::default()
+    };
+    let mut nested = BucketStats::default();
+    page_stats(tx, root, 0, inline, &mut stats, &mut nested)?;
+
+    let page_size = tx.page_size();
+    stats.branch_alloc = (stats.branch_pages + stats.branch_overflow) * page_size;
+    stats.leaf_alloc = (stats.leaf_pages + stats.leaf_overflow) * page_size;
+    stats.depth += nested.depth;
+    stats.add(&nested);
+    Ok(stats)
+}
+
+/// Adds the page and the pages below it to `stats`, and nested buckets found
+/// in its leaves to `nested`.
+fn page_stats<'tx>(
+    tx: &'tx Tx,
+    page: Page<'tx>,
+    depth: usize,
+    inline: bool,
+    stats: &mut BucketStats,
+    nested: &mut BucketStats,
+) -> Result<()> {
+    stats.depth = stats.depth.max(depth + 1);
+    let count = page.count() as usize;
+    if page.is_branch() {
+        let mut in_use = PAGE_HEADER_SIZE + count * BRANCH_PAGE_ELEMENT_SIZE;
+        for elem in page.branch_page_elements() {
+            let elem = elem?;
+            in_use += elem.key().len();
+            page_stats(
+                tx,
+                tx.page(elem.page_id())?,
+                depth + 1,
+                false,
+                stats,
+                nested,
+            )?;
+        }
+        stats.branch_pages += 1;
+        stats.branch_overflow += page.overflow() as usize;
+        stats.branch_in_use += in_use;
+        return Ok(());
+    }
+
+    let mut in_use = PAGE_HEADER_SIZE + count * LEAF_PAGE_ELEMENT_SIZE;
+    for elem in page.leaf_page_elements() {
+        let elem = elem?;
+        in_use += elem.key().len() + elem.value().len();
+        if elem.flag() & BUCKET_LEAF_FLAG as u32 == 0 {
+            continue;
+        }
+        let header = BucketHeader::read(elem.value())
+            .ok_or_else(|| page.corrupt("bucket header out of bounds"))?;
+        let child = if header.root == 0 {
+            let root = Page::from_bytes(&elem.value()[BUCKET_HEADER_SIZE..])?;
+            bucket_stats(tx, root, true)?
+        } else {
+            bucket_stats(tx, tx.page(header.root)?, false)?
+        };
+        nested.add(&child);
+    }
+    stats.keys += count;
+    if inline {
+        stats.inline_bucket_in_use += in_use;
+    } else {
+        stats.leaf_pages += 1;
+        stats.leaf_overflow += page.overflow() as usize;
+        stats.leaf_in_use += in_use;
+    }
+    Ok(())
+}
+
+fn check_key(key: &[u8]) -> Result<()> {
+    if key.is_empty() {
+        return Err(Error::KeyRequired);
+    }
+    if key.len() > MAX_KEY_SIZE {
+        return Err(Error::KeyTooLarge);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bucket::{
+        BUCKET_HEADER_SIZE, DEFAULT_FILL_PERCENT, MAX_FILL_PERCENT, MAX_KEY_SIZE, MIN_FILL_PERCENT,
+    };
+    use crate::db::DB;
+    use crate::error::Error;
+    use crate::page::{LEAF_PAGE_ELEMENT_SIZE, PAGE_HEADER_SIZE};
+
+    #[test]
+    fn test_bucket_put_get_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = DB::open(&path).unwrap();
+
+        db.update(|tx| {
+            let widgets = tx.create_bucket(b"widgets")?;
+            widgets.put(b"foo", b"bar")?;
+            widgets.put(b"baz", b"bat")?;
+            widgets.put(b"foo", b"qux")?;
+            assert_eq!(widgets.get(b"foo")?, Some(&b"qux"[..]));
+            widgets.delete(b"baz")?;
+            assert_eq!(widgets.get(b"baz")?, None);
+            Ok(())
+        })
+        .unwrap();
+        drop(db);
+
+        let db = DB::open(&path).unwrap();
+        db.view(|tx| {
+            let widgets = tx.bucket(b"widgets")?.unwrap();
+            assert_eq!(widgets.get(b"foo")?, Some(&b"qux"[..]));
+            assert_eq!(widgets.get(b"baz")?, None);
+            assert!(tx.bucket(b"gadgets")?.is_none());
+            assert!(matches!(widgets.put(b"a", b"b"), Err(Error::TxNotWritable)));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_bucket_rejects_invalid_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db")).unwrap();
+
+        db.update(|tx| {
+            let widgets = tx.create_bucket(b"widgets")?;
+            assert!(matches!(widgets.put(b"", b"bar"), Err(Error::KeyRequired)));
+            let key = vec![b'k'; MAX_KEY_SIZE + 1];
+            assert!(matches!(widgets.put(&key, b"bar"), Err(Error::KeyTooLarge)));
+            widgets.put(&key[1..], b"bar")?;
+            assert!(matches!(tx.create_bucket(b""), Err(Error::KeyRequired)));
+            assert!(matches!(
+                tx.create_bucket(b"widgets"),
+                Err(Error::BucketExists)
+            ));
+            assert_eq!(
+                tx.delete_bucket(b"gadgets").unwrap_err().to_string(),
+                "bucket not found"
+            );
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_inline_buckets() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db")).unwrap();
+
+        db.update(|tx| {
+            let users = tx.create_bucket(b"users")?;
+            for i in 0..200u32 {
+                let user = users.create_bucket(format!("user-{i:03}").as_bytes())?;
+                user.put(b"id", &i.to_le_bytes())?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        // Every user bucket lives inside the pages of "users" instead of
+        // taking a page of its own.
+        assert!(db.meta().page_id < 20);
+
+        db.update(|tx| {
+            let users = tx.bucket(b"users")?.unwrap();
+            let user = users.bucket(b"user-007")?.unwrap();
+            assert_eq!(user.get(b"id")?, Some(&7u32.to_le_bytes()[..]));
+            assert_eq!(user.header().root, 0);
+            for i in 0..50u32 {
+                user.put(format!("key-{i:03}").as_bytes(), &[0xab; 16])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        db.update(|tx| {
+            let user = tx.bucket(b"users")?.unwrap().bucket(b"user-007")?.unwrap();
+            assert_ne!(user.header().root, 0);
+            assert_eq!(user.get(b"key-042")?, Some(&[0xab; 16][..]));
+            for i in 0..50u32 {
+                user.delete(format!("key-{i:03}").as_bytes())?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        db.view(|tx| {
+            let user = tx.bucket(b"users")?.unwrap().bucket(b"user-007")?.unwrap();
+            assert_eq!(user.header().root, 0);
+            assert_eq!(user.get(b"id")?, Some(&7u32.to_le_bytes()[..]));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_large_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = DB::open(&path).unwrap();
+        let value = |i: usize| vec![i as u8; 20 * 1024 * (i + 1)];
+
+        db.update(|tx| {
+            let b = tx.create_bucket(b"documents")?;
+            for i in 0..10 {
+                b.put(format!("doc-{i}").as_bytes(), &value(i))?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        drop(db);
+
+        let db = DB::open(&path).unwrap();
+        db.view(|tx| {
+            let b = tx.bucket(b"documents")?.unwrap();
+            for i in 0..10 {
+                assert_eq!(b.get(format!("doc-{i}").as_bytes())?, Some(&value(i)[..]));
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        // Replacing the documents frees every page of their spans, which are
+        // then reused for the new versions.
+        db.update(|tx| {
+            let b = tx.bucket(b"documents")?.unwrap();
+            for i in 0..10 {
+                b.put(format!("doc-{i}").as_bytes(), &value(9 - i))?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        let page_id = db.meta().page_id;
+        let freed = db.freelist().count();
+        assert!(freed * db.page_size() > 1024 * 1024);
+
+        db.update(|tx| {
+            let b = tx.bucket(b"documents")?.unwrap();
+            for i in 0..10 {
+                b.put(format!("doc-{i}").as_bytes(), &value(i))?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(db.meta().page_id, page_id);
+        db.view(|tx| {
+            let b = tx.bucket(b"documents")?.unwrap();
+            assert_eq!(b.get(b"doc-9")?, Some(&value(9)[..]));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_fill_percent() {
+        let dir = tempfile::tempdir().unwrap();
+        let fill = |fill_percent: f64| {
+            let db = DB::open(dir.path().join(fill_percent.to_string())).unwrap();
+            db.update(|tx| {
+                let b = tx.create_bucket(b"series")?;
+                b.set_fill_percent(fill_percent);
+                assert_eq!(b.fill_percent(), fill_percent);
+                for i in 0..5000u64 {
+                    b.put(&i.to_be_bytes(), &[0; 32])?;
+                }
+                Ok(())
+            })
+            .unwrap();
+            db.meta().page_id
+        };
+
+        // Appending in key order fills every page up to the threshold.
+        let (half, full) = (fill(0.5), fill(1.0));
+        assert!(full * 10 < half * 6, "{full} pages at 100%, {half} at 50%");
+    }
+
+    #[test]
+    fn test_fill_percent_is_clamped() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db")).unwrap();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for (fill_percent, expected) in [
+                (5.0, MAX_FILL_PERCENT),
+                (f64::INFINITY, MAX_FILL_PERCENT),
+                (0.0, MIN_FILL_PERCENT),
+                (-1.0, MIN_FILL_PERCENT),
+                (0.75, 0.75),
+                (f64::NAN, DEFAULT_FILL_PERCENT),
+            ] {
+                b.set_fill_percent(fill_percent);
+                assert_eq!(b.fill_percent(), expected);
+            }
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_sequence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = DB::open(&path).unwrap();
+
+        db.update(|tx| {
+            let users = tx.create_bucket(b"users")?;
+            assert_eq!(users.sequence(), 0);
+            assert_eq!(users.next_sequence()?, 1);
+            assert_eq!(users.next_sequence()?, 2);
+            users.create_bucket(b"admins")?.set_sequence(1000)?;
+            Ok(())
+        })
+        .unwrap();
+
+        // A rolled back transaction does not consume ids.
+        let tx = db.begin(true).unwrap();
+        assert_eq!(
+            tx.bucket(b"users")
+                .unwrap()
+                .unwrap()
+                .next_sequence()
+                .unwrap(),
+            3
+        );
+        tx.rollback().unwrap();
+        drop(db);
+
+        let db = DB::open(&path).unwrap();
+        db.update(|tx| {
+            let users = tx.bucket(b"users")?.unwrap();
+            assert_eq!(users.next_sequence()?, 3);
+            assert_eq!(users.bucket(b"admins")?.unwrap().sequence(), 1000);
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            let users = tx.bucket(b"users")?.unwrap();
+            assert_eq!(users.sequence(), 3);
+            assert!(matches!(users.next_sequence(), Err(Error::TxNotWritable)));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_bucket_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db")).unwrap();
+
+        db.update(|tx| {
+            let widgets = tx.create_bucket(b"widgets")?;
+            for i in 0..500u32 {
+                widgets.put(&i.to_be_bytes(), &[0; 100])?;
+            }
+            widgets.create_bucket(b"inline")?.put(b"foo", b"bar")?;
+            tx.create_bucket(b"large")?.put(b"big", &[1; 10_000])?;
+
+            // Only committed pages are counted, so a new bucket is still its
+            // empty inline leaf.
+            let stats = tx.create_bucket(b"empty")?.stats()?;
+            assert_eq!(stats.inline_buckets, 1);
+            assert_eq!(stats.inline_bucket_in_use, PAGE_HEADER_SIZE);
+            assert_eq!(stats.keys, 0);
+            Ok(())
+        })
+        .unwrap();
+
+        db.view(|tx| {
+            let stats = tx.bucket(b"widgets")?.unwrap().stats()?;
+            assert_eq!(stats.buckets, 2);
+            assert_eq!(stats.inline_buckets, 1);
+            assert_eq!(stats.keys, 502);
+            assert_eq!(stats.depth, 3);
+            assert_eq!(stats.branch_pages, 1);
+            assert!(stats.leaf_pages > 10);
+            assert_eq!(stats.leaf_overflow, 0);
+            assert_eq!(stats.leaf_alloc, stats.leaf_pages * 4096);
+            let leaf_data = 501 * LEAF_PAGE_ELEMENT_SIZE + 500 * 104 + 6 + BUCKET_HEADER_SIZE;
+            assert!(stats.leaf_in_use > leaf_data);
+            assert!(stats.leaf_in_use < stats.leaf_alloc);
+            assert_eq!(
+                stats.inline_bucket_in_use,
+                PAGE_HEADER_SIZE + LEAF_PAGE_ELEMENT_SIZE + 6
+            );
+
+            let stats = tx.bucket(b"large")?.unwrap().stats()?;
+            assert_eq!((stats.leaf_pages, stats.leaf_overflow), (1, 2));
+            assert_eq!(stats.leaf_alloc, 3 * 4096);
+            assert_eq!(stats.depth, 1);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_nested_buckets() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db")).unwrap();
+
+        db.update(|tx| {
+            let users = tx.create_bucket(b"users")?;
+            let alice = users.create_bucket(b"alice")?;
+            alice.put(b"email", b"alice@example.com")?;
+            users.put(b"count", b"1")?;
+
+            assert!(matches!(
+                tx.create_bucket(b"users"),
+                Err(Error::BucketExists)
+            ));
+            assert!(matches!(
+                users.create_bucket(b"count"),
+                Err(Error::IncompatibleValue)
+            ));
+            assert!(matches!(
+                users.put(b"alice", b"x"),
+                Err(Error::IncompatibleValue)
+            ));
+            assert_eq!(users.get(b"alice")?, None);
+            Ok(())
+        })
+        .unwrap();
+
+        db.update(|tx| {
+            let users = tx.create_bucket_if_not_exists(b"users")?;
+            let alice = users.bucket(b"alice")?.unwrap();
+            assert_eq!(alice.get(b"email")?, Some(&b"alice@example.com"[..]));
+            users
+                .create_bucket(b"bob")?
+                .put(b"email", b"bob@example.com")?;
+            Ok(())
+        })
+        .unwrap();
+
+        db.update(|tx| {
+            let users = tx.bucket(b"users")?.unwrap();
+            let bob = users.bucket(b"bob")?.unwrap();
+            assert_eq!(bob.get(b"email")?, Some(&b"bob@example.com"[..]));
+            users.delete_bucket(b"alice")?;
+            assert!(matches!(
+                users.delete_bucket(b"alice"),
+                Err(Error::BucketNotFound)
+            ));
+            Ok(())
+        })
+        .unwrap();
+
+        db.view(|tx| {
+            let users = tx.bucket(b"users")?.unwrap();
+            assert!(users.bucket(b"alice")?.is_none());
+            assert!(users.bucket(b"bob")?.is_some());
+            Ok(())
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,338 @@
+use crate::bucket::{BucketHeader, BUCKET_HEADER_SIZE};
+use crate::error::Error;
+use crate::page::{
+    Meta, Page, PageId, BUCKET_LEAF_FLAG, FREELIST_PAGE_FLAG, META_PAGE_FLAG, NO_FREELIST,
+};
+use crate::transaction::Tx;
+
+/// Walks every page reachable from `meta` and returns all the problems found,
+/// each reported as `Error::Corrupt` on the page it was found on.
+pub(crate) fn check(tx: &Tx, meta: &Meta) -> Vec<Error> {
+    if meta.page_id < 2 || meta.page_id > tx.mapped_pages() {
+        return vec![Error::Corrupt {
+            page_id: meta.tx_id % 2,
+            reason: "high-water mark out of range",
+        }];
+    }
+    let mut checker = Checker {
+        tx,
+        reachable: vec![false; meta.page_id as usize],
+        freed: vec![false; meta.page_id as usize],
+        errors: Vec::new(),
+    };
+    checker.reachable[..2].fill(true);
+    for page_id in 0..2 {
+        match tx.page(page_id) {
+            Ok(page) => {
+                checker.check_type(&page, META_PAGE_FLAG);
+            }
+            Err(err) => checker.errors.push(err),
+        }
+    }
+
+    // Without a freelist on disk, the free pages are the ones nothing
+    // reaches, so there are no unreachable pages to report.
+    let synced = meta.freelist != NO_FREELIST;
+    if synced {
+        let page = checker.visit(meta.freelist);
+        if let Some(page) = page.filter(|page| checker.check_type(page, FREELIST_PAGE_FLAG)) {
+            match page.freelist_page_ids() {
+                Ok(ids) => checker.mark_freed(meta.freelist, ids),
+                Err(err) => checker.errors.push(err.into()),
+            }
+        }
+    } else {
+        let ids = tx.db().freelist().free_ids();
+        checker.mark_freed(NO_FREELIST, ids);
+    }
+    checker.check_tree(meta.root, None, None);
+
+    if synced {
+        for id in 2..meta.page_id {
+            if !checker.reachable[id as usize] && !checker.freed[id as usize] {
+                checker.corrupt(id, "page neither reachable nor freed");
+            }
+        }
+    }
+    checker.errors
+}
+
+struct Checker<'a, 'db> {
+    tx: &'a Tx<'db>,
+    reachable: Vec<bool>,
+    freed: Vec<bool>,
+    errors: Vec<Error>,
+}
+
+impl<'a> Checker<'a, '_> {
+    fn corrupt(&mut self, page_id: PageId, reason: &'static str) {
+        self.errors.push(Error::Corrupt { page_id, reason });
+    }
+
+    fn mark_freed(&mut self, freelist: PageId, ids: Vec<PageId>) {
+        for id in ids {
+            match self.freed.get_mut(id as usize) {
+                _ if id < 2 => self.corrupt(freelist, "meta page on the freelist"),
+                Some(freed) if !*freed => *freed = true,
+                Some(_) => self.corrupt(id, "page freed twice"),
+                None => self.corrupt(id, "freed page above the high-water mark"),
+            }
+        }
+    }
+
+    /// Reports the page unless its flag is exactly `flag`.
+    fn check_type(&mut self, page: &Page, flag: u8) -> bool {
+        if page.flag() != flag as u16 {
+            self.corrupt(page.id(), "unexpected page type");
+            return false;
+        }
+        true
+    }
+
+    /// Marks the page and its overflow pages as reachable. Returns the page,
+    /// unless it cannot be read or was already reached, in which case
+    /// following it again would report the same problems twice.
+    fn visit(&mut self, page_id: PageId) -> Option<Page<'a>> {
+        if page_id >= self.reachable.len() as PageId {
+            self.corrupt(page_id, "page above the high-water mark");
+            return None;
+        }
+        let page = match self.tx.page(page_id) {
+            Ok(page) => page,
+            Err(err) => {
+                self.errors.push(err);
+                return None;
+            }
+        };
+        for id in page_id..=page_id + page.overflow() as PageId {
+            match self.reachable.get_mut(id as usize) {
+                Some(seen) if !*seen => *seen = true,
+                Some(_) => {
+                    self.corrupt(id, "page reachable twice");
+                    return None;
+                }
+                None => {
+                    self.corrupt(id, "page above the high-water mark");
+                    return None;
+                }
+            }
+            if self.freed[id as usize] {
+                self.corrupt(id, "page both reachable and freed");
+            }
+        }
+        Some(page)
+    }
+
+    /// Checks the tree rooted at `page_id`, whose keys must all be at least
+    /// `lower` and below `upper`.
+    fn check_tree(&mut self, page_id: PageId, lower: Option<&[u8]>, upper: Option<&[u8]>) {
+        let Some(page) = self.visit(page_id) else {
+            return;
+        };
+        if !page.is_branch() && !page.is_leaf() {
+            self.corrupt(page_id, "unexpected page type");
+            return;
+        }
+        self.check_page(&page, lower, upper);
+    }
+
+    fn check_page(&mut self, page: &Page<'a>, lower: Option<&[u8]>, upper: Option<&[u8]>) {
+        if page.is_branch() {
+            let elems: Result<Vec<_>, _> = page.branch_page_elements().collect();
+            let elems = match elems {
+                Ok(elems) => elems,
+                Err(err) => return self.errors.push(err.into()),
+            };
+            let keys: Vec<&[u8]> = elems.iter().map(|elem| elem.key()).collect();
+            self.check_keys(page, &keys, lower, upper);
+            for (i, elem) in elems.iter().enumerate() {
+                let upper = keys.get(i + 1).copied().or(upper);
+                self.check_tree(elem.page_id(), Some(elem.key()), upper);
+            }
+            return;
+        }
+
+        let elems: Result<Vec<_>, _> = page.leaf_page_elements().collect();
+        let elems = match elems {
+            Ok(elems) => elems,
+            Err(err) => return self.errors.push(err.into()),
+        };
+        let keys: Vec<&[u8]> = elems.iter().map(|elem| elem.key()).collect();
+        self.check_keys(page, &keys, lower, upper);
+        for elem in elems {
+            if elem.flag() & BUCKET_LEAF_FLAG as u32 == 0 {
+                continue;
+            }
+            let Some(header) = BucketHeader::read(elem.value()) else {
+                self.corrupt(page.id(), "bucket header out of bounds");
+                continue;
+            };
+            if header.root != 0 {
+                self.check_tree(header.root, None, None);
+                continue;
+            }
+            match Page::from_bytes(&elem.value()[BUCKET_HEADER_SIZE..]) {
+                Ok(inline) if inline.is_leaf() => self.check_page(&inline, None, None),
+                Ok(_) => self.corrupt(page.id(), "inline bucket is not a leaf"),
+                Err(_) => self.corrupt(page.id(), "inline bucket out of bounds"),
+            }
+        }
+    }
+
+    /// Reports keys of the page that are out of order or fall outside of the
+    /// range its parent assigns to the page, once for each kind of problem.
+    fn check_keys(
+        &mut self,
+        page: &Page,
+        keys: &[&[u8]],
+        lower: Option<&[u8]>,
+        upper: Option<&[u8]>,
+    ) {
+        if keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+            self.corrupt(page.id(), "keys out of order");
+        }
+        let below = |key: &&[u8]| lower.is_some_and(|lower| *key < lower);
+        let above = |key: &&[u8]| upper.is_some_and(|upper| *key >= upper);
+        if keys.iter().any(|key| below(key) || above(key)) {
+            self.corrupt(page.id(), "key outside of its branch range");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::FileExt;
+    use std::path::Path;
+
+    use crate::db::{Options, DB};
+    use crate::error::Error;
+    use crate::page::{PageId, FREELIST_PAGE_FLAG, LEAF_PAGE_FLAG, PAGE_HEADER_SIZE};
+
+    const PAGE_SIZE: u64 = 4096;
+
+    fn set_flag(path: &Path, page_id: PageId, flag: u8) {
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        file.write_all_at(&(flag as u16).to_le_bytes(), page_id * PAGE_SIZE + 8)
+            .unwrap();
+    }
+
+    /// Checks the database, opened read-only so that a damaged freelist is
+    /// not loaded.
+    fn check(path: &Path) -> Vec<(PageId, &'static str)> {
+        let options = Options {
+            read_only: true,
+            ..Options::default()
+        };
+        let db = DB::open_with_options(path, options).unwrap();
+        db.view(|tx| Ok(tx.check().collect::<Vec<_>>()))
+            .unwrap()
+            .into_iter()
+            .map(|err| match err {
+                Error::Corrupt { page_id, reason } => (page_id, reason),
+                err => panic!("unexpected error {err}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = DB::open(&path).unwrap();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..1000u32 {
+                b.put(&i.to_be_bytes(), &[0; 64])?;
+            }
+            b.create_bucket(b"inline")?.put(b"foo", b"bar")?;
+            let gadgets = tx.create_bucket(b"gadgets")?;
+            gadgets.put(b"large", &[1; 10_000])
+        })
+        .unwrap();
+        // Leave a few pages on the freelist.
+        db.update(|tx| tx.delete_bucket(b"gadgets")).unwrap();
+
+        let (leaves, freelist) = db
+            .view(|tx| {
+                assert_eq!(tx.check().count(), 0);
+                let root = tx.bucket(b"widgets")?.unwrap().header().root;
+                let leaves: Vec<_> = tx
+                    .page(root)?
+                    .branch_page_elements()
+                    .map(|elem| elem.unwrap().page_id())
+                    .collect();
+                Ok((leaves, db.meta().freelist))
+            })
+            .unwrap();
+        drop(db);
+
+        // Turn one leaf into a freelist page, and put another one on the
+        // freelist while it is still in use.
+        set_flag(&path, leaves[0], FREELIST_PAGE_FLAG);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut buf = vec![0; PAGE_SIZE as usize];
+        file.read_exact_at(&mut buf, freelist * PAGE_SIZE).unwrap();
+        let count = u16::from_le_bytes([buf[10], buf[11]]) as usize;
+        let mut ids: Vec<u64> = buf[PAGE_HEADER_SIZE..][..count * 8]
+            .chunks(8)
+            .map(|id| u64::from_le_bytes(id.try_into().unwrap()))
+            .collect();
+        ids.push(leaves[1]);
+        ids.sort_unstable();
+        for (i, id) in ids.iter().enumerate() {
+            buf[PAGE_HEADER_SIZE + i * 8..][..8].copy_from_slice(&id.to_le_bytes());
+        }
+        buf[10..12].copy_from_slice(&(ids.len() as u16).to_le_bytes());
+        file.write_all_at(&buf, freelist * PAGE_SIZE).unwrap();
+        drop(file);
+
+        assert_eq!(
+            check(&path),
+            [
+                (leaves[0], "unexpected page type"),
+                (leaves[1], "page both reachable and freed"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_meta_page_flags() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        DB::open(&path)
+            .unwrap()
+            .update(|tx| tx.create_bucket(b"widgets").map(|_| ()))
+            .unwrap();
+
+        set_flag(&path, 0, LEAF_PAGE_FLAG);
+        set_flag(&path, 1, FREELIST_PAGE_FLAG);
+        assert_eq!(
+            check(&path),
+            [(0, "unexpected page type"), (1, "unexpected page type")]
+        );
+    }
+
+    #[test]
+    fn test_check_freelist_page_flag() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = DB::open(&path).unwrap();
+        db.update(|tx| tx.create_bucket(b"widgets").map(|_| ()))
+            .unwrap();
+        let freelist = db.meta().freelist;
+        drop(db);
+
+        // The free pages cannot be told apart from lost ones any more.
+        set_flag(&path, freelist, LEAF_PAGE_FLAG);
+        let errors = check(&path);
+        assert_eq!(errors[0], (freelist, "unexpected page type"));
+        assert!(errors[1..]
+            .iter()
+            .all(|&(_, reason)| reason == "page neither reachable nor freed"));
+    }
+}
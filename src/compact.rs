@@ -0,0 +1,166 @@
+use crate::bucket::{Bucket, MAX_FILL_PERCENT};
+use crate::db::DB;
+use crate::error::{Error, Result};
+use crate::transaction::Tx;
+
+/// Copies every bucket of `src` into `dst`, which is expected to be empty, in
+/// key order and with pages filled completely. The copy is committed every
+/// time `tx_max_size` bytes of keys and values have been written, or once at
+/// the end if it is 0.
+pub fn compact(src: &DB, dst: &DB, tx_max_size: usize) -> Result<()> {
+    let mut writer = Writer {
+        dst,
+        tx: Some(dst.begin(true)?),
+        size: 0,
+        tx_max_size,
+    };
+    src.view(|tx| copy_bucket(&tx.root(), &mut Vec::new(), &mut writer))?;
+    writer.tx().commit()
+}
+
+/// Writes to the destination, committing whenever a transaction grows past
+/// the limit. Buckets are looked up by their path every time, since they
+/// cannot outlive the transaction they were opened in.
+struct Writer<'db> {
+    dst: &'db DB,
+    tx: Option<Tx<'db>>,
+    size: usize,
+    tx_max_size: usize,
+}
+
+impl<'db> Writer<'db> {
+    fn tx(&mut self) -> Tx<'db> {
+        self.tx.take().expect("writer has an open transaction")
+    }
+
+    fn reserve(&mut self, size: usize) -> Result<()> {
+        if self.tx_max_size != 0 && self.size + size > self.tx_max_size {
+            self.tx().commit()?;
+            self.tx = Some(self.dst.begin(true)?);
+            self.size = 0;
+        }
+        self.size += size;
+        Ok(())
+    }
+
+    fn bucket(&self, path: &[Vec<u8>]) -> Result<Bucket<'_>> {
+        let tx = self.tx.as_ref().expect("writer has an open transaction");
+        let mut bucket = tx.root();
+        for name in path {
+            bucket = bucket.bucket(name)?.ok_or(Error::BucketNotFound)?;
+        }
+        bucket.set_fill_percent(MAX_FILL_PERCENT);
+        Ok(bucket)
+    }
+
+    fn put(&mut self, path: &[Vec<u8>], key: &[u8], value: &[u8]) -> Result<()> {
+        self.reserve(key.len() + value.len())?;
+        self.bucket(path)?.put(key, value)
+    }
+
+    fn create_bucket(&mut self, path: &[Vec<u8>], name: &[u8], sequence: u64) -> Result<()> {
+        self.reserve(name.len())?;
+        let bucket = self.bucket(path)?.create_bucket(name)?;
+        bucket.set_sequence(sequence)
+    }
+}
+
+/// Copies the items of `src`, found at `path` in the source, to the same path
+/// in the destination.
+fn copy_bucket(src: &Bucket, path: &mut Vec<Vec<u8>>, writer: &mut Writer) -> Result<()> {
+    for item in src.iter() {
+        let (key, value) = item?;
+        if let Some(value) = value {
+            writer.put(path, key, value)?;
+            continue;
+        }
+        let child = src.bucket(key)?.ok_or(Error::BucketNotFound)?;
+        writer.create_bucket(path, key, child.sequence())?;
+        path.push(key.to_vec());
+        copy_bucket(&child, path, writer)?;
+        path.pop();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bucket::Bucket;
+    use crate::compact::compact;
+    use crate::db::DB;
+    use crate::error::Result;
+
+    /// Flattens the bucket into (depth, key, value, sequence) rows.
+    fn dump(
+        bucket: &Bucket,
+        depth: usize,
+        rows: &mut Vec<(usize, Vec<u8>, Vec<u8>, u64)>,
+    ) -> Result<()> {
+        for item in bucket.iter() {
+            let (key, value) = item?;
+            match value {
+                Some(value) => rows.push((depth, key.to_vec(), value.to_vec(), 0)),
+                None => {
+                    let child = bucket.bucket(key)?.unwrap();
+                    rows.push((depth, key.to_vec(), Vec::new(), child.sequence()));
+                    dump(&child, depth + 1, rows)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = DB::open(dir.path().join("src")).unwrap();
+        src.update(|tx| {
+            let widgets = tx.create_bucket(b"widgets")?;
+            widgets.set_sequence(42)?;
+            for i in 0..5000u32 {
+                widgets.put(&i.to_be_bytes(), &[i as u8; 100])?;
+            }
+            let users = tx.create_bucket(b"users")?;
+            for i in 0..50u32 {
+                let user = users.create_bucket(format!("user-{i:02}").as_bytes())?;
+                user.put(b"id", &i.to_le_bytes())?;
+                user.next_sequence()?;
+            }
+            tx.create_bucket(b"empty")?;
+            Ok(())
+        })
+        .unwrap();
+        src.update(|tx| {
+            let widgets = tx.bucket(b"widgets")?.unwrap();
+            for i in (0..5000u32).filter(|i| i % 10 != 0) {
+                widgets.delete(&i.to_be_bytes())?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        let dst = DB::open(dir.path().join("dst")).unwrap();
+        compact(&src, &dst, 4096).unwrap();
+        assert!(dst.stats().tx_stats.writes > 10);
+
+        let rows = |db: &DB| {
+            db.view(|tx| {
+                let mut rows = Vec::new();
+                dump(&tx.root(), 0, &mut rows)?;
+                assert_eq!(tx.check().count(), 0);
+                Ok(rows)
+            })
+            .unwrap()
+        };
+        let expected = rows(&src);
+        assert_eq!(expected.len(), 3 + 500 + 100);
+        assert_eq!(rows(&dst), expected);
+
+        let stats = |db: &DB| {
+            db.view(|tx| tx.bucket(b"widgets")?.unwrap().stats())
+                .unwrap()
+        };
+        assert!(stats(&dst).leaf_pages < stats(&src).leaf_pages);
+        assert!(dst.meta().page_id < src.meta().page_id);
+    }
+}
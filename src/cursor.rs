@@ -0,0 +1,507 @@
+use std::cmp::Ordering;
+use std::mem;
+
+use crate::bucket::{Bucket, PageNode};
+use crate::error::{Error, Result};
+use crate::node::NodeId;
+use crate::page::{Page, PageId, BUCKET_LEAF_FLAG};
+use crate::transaction::Slice;
+
+/// A key/value pair returned by a cursor. Keys holding a nested bucket have no
+/// value.
+pub type Item<'tx> = (&'tx [u8], Option<&'tx [u8]>);
+
+/// Walks the keys of a bucket in order. The cursor keeps the path from the
+/// root of the bucket down to the current leaf element, reading either mapped
+/// pages or the nodes a writable transaction has materialized for them.
+pub struct Cursor<'tx> {
+    bucket: Bucket<'tx>,
+    stack: Vec<ElemRef<'tx>>,
+    /// Set by `delete`, which leaves the cursor on the item after the deleted
+    /// one, so that `next` returns that item instead of moving past it.
+    deleted: bool,
+}
+
+/// A position within a page or a node.
+#[derive(Clone, Copy)]
+struct ElemRef<'tx> {
+    page: Option<Page<'tx>>,
+    node: Option<NodeId>,
+    index: usize,
+}
+
+impl<'tx> ElemRef<'tx> {
+    fn new((page, node): PageNode<'tx>, index: usize) -> ElemRef<'tx> {
+        ElemRef { page, node, index }
+    }
+}
+
+impl<'tx> Cursor<'tx> {
+    pub(crate) fn new(bucket: Bucket<'tx>) -> Cursor<'tx> {
+        Cursor {
+            bucket,
+            stack: Vec::new(),
+            deleted: false,
+        }
+    }
+
+    pub fn bucket(&self) -> Bucket<'tx> {
+        self.bucket
+    }
+
+    /// Moves to the first item of the bucket.
+    pub fn first(&mut self) -> Result<Option<Item<'tx>>> {
+        self.deleted = false;
+        self.stack.clear();
+        let root = self.bucket.root_elem()?;
+        self.stack.push(ElemRef::new(root, 0));
+        self.go_first()?;
+
+        // The root of an empty bucket is an empty leaf.
+        if self.count(self.top()) == 0 {
+            return self.next();
+        }
+        self.item()
+    }
+
+    /// Moves to the last item of the bucket.
+    pub fn last(&mut self) -> Result<Option<Item<'tx>>> {
+        self.deleted = false;
+        self.stack.clear();
+        let root = self.bucket.root_elem()?;
+        let mut elem = ElemRef::new(root, 0);
+        elem.index = self.count(&elem).saturating_sub(1);
+        self.stack.push(elem);
+        self.go_last()?;
+
+        // The last leaf may have been emptied by deletes.
+        if self.count(self.top()) == 0 {
+            return self.prev();
+        }
+        self.item()
+    }
+
+    /// Moves to the next item, returning `None` once past the last one.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Option<Item<'tx>>> {
+        if mem::take(&mut self.deleted) {
+            if let Some(top) = self.stack.last() {
+                if top.index < self.count(top) {
+                    return self.item();
+                }
+            }
+        }
+        loop {
+            // Find the deepest level that still has elements to the right.
+            let Some(depth) = self
+                .stack
+                .iter()
+                .rposition(|elem| elem.index + 1 < self.count(elem))
+            else {
+                if let Some(&top) = self.stack.last() {
+                    let count = self.count(&top);
+                    self.stack.last_mut().unwrap().index = count;
+                }
+                return Ok(None);
+            };
+            self.stack.truncate(depth + 1);
+            self.stack[depth].index += 1;
+            self.go_first()?;
+
+            if self.count(self.top()) > 0 {
+                return self.item();
+            }
+        }
+    }
+
+    /// Moves to the previous item, returning `None` once before the first one.
+    pub fn prev(&mut self) -> Result<Option<Item<'tx>>> {
+        self.deleted = false;
+        loop {
+            // Find the deepest level that still has elements to the left.
+            let Some(depth) = self.stack.iter().rposition(|elem| elem.index > 0) else {
+                self.stack.clear();
+                return Ok(None);
+            };
+            self.stack.truncate(depth + 1);
+            self.stack[depth].index -= 1;
+            self.go_last()?;
+
+            if self.count(self.top()) > 0 {
+                return self.item();
+            }
+        }
+    }
+
+    /// Moves to `key`, or to the item right after where it would be. Returns
+    /// `None` if there is no such item.
+    pub fn seek(&mut self, key: &[u8]) -> Result<Option<Item<'tx>>> {
+        self.seek_raw(key)?;
+
+        // The key would go after the last element of the leaf.
+        let top = self.top();
+        if top.index >= self.count(top) {
+            return self.next();
+        }
+        self.item()
+    }
+
+    /// Deletes the item under the cursor, after which `next` returns the item
+    /// that followed it. Nested buckets have to be removed with
+    /// `Bucket::delete_bucket` instead.
+    pub fn delete(&mut self) -> Result<()> {
+        if !self.bucket.tx().writable() {
+            return Err(Error::TxNotWritable);
+        }
+        let Some((key, _, flags)) = self.raw_item()? else {
+            return Ok(());
+        };
+        if flags & BUCKET_LEAF_FLAG as u32 != 0 {
+            return Err(Error::IncompatibleValue);
+        }
+        let node = self.node()?;
+        let mut nodes = self.bucket.tx().nodes.borrow_mut();
+        nodes[node].del(key.as_bytes());
+
+        // The stack may still refer to the pages the nodes were read from,
+        // which hold the deleted key.
+        let mut node = Some(node);
+        for elem in self.stack.iter_mut().rev() {
+            elem.node = node;
+            node = node.and_then(|node| nodes[node].parent);
+        }
+        self.deleted = true;
+        Ok(())
+    }
+
+    /// Positions the cursor on the leaf where `key` is or would be inserted,
+    /// returning the raw key, value and flags found there. Unlike `seek`, the
+    /// cursor stays on that leaf even if the key would go after its last
+    /// element.
+    pub(crate) fn seek_raw(&mut self, key: &[u8]) -> Result<Option<(Slice, Slice, u32)>> {
+        self.deleted = false;
+        self.stack.clear();
+        let root = self.bucket.root_elem()?;
+        self.search(key, root)?;
+        self.raw_item()
+    }
+
+    /// Returns the node holding the current leaf element, materializing every
+    /// node on the path to it.
+    pub(crate) fn node(&self) -> Result<NodeId> {
+        let top = self.top();
+        if let Some(node) = top.node {
+            return Ok(node);
+        }
+        let mut node = match self.stack[0].node {
+            Some(node) => node,
+            None => self.bucket.root_node()?,
+        };
+        for elem in &self.stack[..self.stack.len() - 1] {
+            let page_id = self.bucket.tx().nodes.borrow()[node].inodes[elem.index].page_id;
+            node = self.bucket.node(page_id, Some(node))?;
+        }
+        Ok(node)
+    }
+
+    fn top(&self) -> &ElemRef<'tx> {
+        self.stack.last().expect("cursor is not positioned")
+    }
+
+    fn count(&self, elem: &ElemRef<'tx>) -> usize {
+        match (elem.node, elem.page) {
+            (Some(node), _) => self.bucket.tx().nodes.borrow()[node].inodes.len(),
+            (None, Some(page)) => page.count() as usize,
+            (None, None) => 0,
+        }
+    }
+
+    fn is_leaf(&self, elem: &ElemRef<'tx>) -> bool {
+        match (elem.node, elem.page) {
+            (Some(node), _) => self.bucket.tx().nodes.borrow()[node].is_leaf,
+            (None, Some(page)) => page.is_leaf(),
+            (None, None) => true,
+        }
+    }
+
+    /// Returns the page id of the child the branch element points to.
+    fn child(&self, elem: &ElemRef<'tx>) -> Result<PageId> {
+        match (elem.node, elem.page) {
+            (Some(node), _) => Ok(self.bucket.tx().nodes.borrow()[node].inodes[elem.index].page_id),
+            (None, Some(page)) => Ok(page.branch_page_element(elem.index)?.page_id()),
+            (None, None) => unreachable!(),
+        }
+    }
+
+    /// Descends to the first leaf element below the top of the stack.
+    fn go_first(&mut self) -> Result<()> {
+        loop {
+            let top = *self.top();
+            if self.is_leaf(&top) {
+                return Ok(());
+            }
+            let child = self.bucket.page_node(self.child(&top)?)?;
+            self.stack.push(ElemRef::new(child, 0));
+        }
+    }
+
+    /// Descends to the last leaf element below the top of the stack.
+    fn go_last(&mut self) -> Result<()> {
+        loop {
+            let top = *self.top();
+            if self.is_leaf(&top) {
+                return Ok(());
+            }
+            let child = self.bucket.page_node(self.child(&top)?)?;
+            let mut elem = ElemRef::new(child, 0);
+            elem.index = self.count(&elem).saturating_sub(1);
+            self.stack.push(elem);
+        }
+    }
+
+    fn search(&mut self, key: &[u8], (page, node): PageNode<'tx>) -> Result<()> {
+        let mut elem = ElemRef::new((page, node), 0);
+        if let Some(page) = page.filter(|page| !page.is_leaf() && !page.is_branch()) {
+            return Err(page.corrupt("not a branch or leaf page").into());
+        }
+
+        let leaf = self.is_leaf(&elem);
+        let (index, exact) = match (node, page) {
+            (Some(node), _) => self.bucket.tx().nodes.borrow()[node].search(key),
+            (None, Some(page)) => search_page(&page, key)?,
+            (None, None) => (0, false),
+        };
+        elem.index = index;
+        if leaf {
+            self.stack.push(elem);
+            return Ok(());
+        }
+
+        // Branch keys are the first key of each child, so unless the key is an
+        // exact match it belongs to the previous child.
+        if !exact && index > 0 {
+            elem.index -= 1;
+        }
+        self.stack.push(elem);
+        let child = self.bucket.page_node(self.child(&elem)?)?;
+        self.search(key, child)
+    }
+
+    fn raw_item(&self) -> Result<Option<(Slice, Slice, u32)>> {
+        let Some(top) = self.stack.last() else {
+            return Ok(None);
+        };
+        if top.index >= self.count(top) {
+            return Ok(None);
+        }
+        match (top.node, top.page) {
+            (Some(node), _) => {
+                let nodes = self.bucket.tx().nodes.borrow();
+                let inode = &nodes[node].inodes[top.index];
+                Ok(Some((inode.key, inode.value, inode.flags)))
+            }
+            (None, Some(page)) => {
+                let elem = page.leaf_page_element(top.index)?;
+                Ok(Some((
+                    Slice::new(elem.key()),
+                    Slice::new(elem.value()),
+                    elem.flag(),
+                )))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn item(&self) -> Result<Option<Item<'tx>>> {
+        Ok(self
+            .raw_item()?
+            .map(|(key, value, flags)| self.to_item(key, value, flags)))
+    }
+
+    fn to_item(&self, key: Slice, value: Slice, flags: u32) -> Item<'tx> {
+        let tx = self.bucket.tx();
+        let value = if flags & BUCKET_LEAF_FLAG as u32 != 0 {
+            None
+        } else {
+            Some(tx.bytes(value))
+        };
+        (tx.bytes(key), value)
+    }
+}
+
+/// Binary searches the elements of a branch or leaf page, decoding only the
+/// elements it probes.
+fn search_page(page: &Page, key: &[u8]) -> Result<(usize, bool)> {
+    let (mut lo, mut hi) = (0, page.count() as usize);
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        let elem_key = if page.is_leaf() {
+            page.leaf_page_element(mid)?.key()
+        } else {
+            page.branch_page_element(mid)?.key()
+        };
+        match elem_key.cmp(key) {
+            Ordering::Less => lo = mid + 1,
+            Ordering::Equal => return Ok((mid, true)),
+            Ordering::Greater => hi = mid,
+        }
+    }
+    Ok((lo, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::DB;
+    use crate::error::Error;
+
+    #[test]
+    fn test_cursor_navigation() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db")).unwrap();
+
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for key in [&b"foo"[..], b"bar", b"baz", b"qux"] {
+                b.put(key, &[key[0]])?;
+            }
+            b.create_bucket(b"sub")?;
+            Ok(())
+        })
+        .unwrap();
+
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?.unwrap();
+            let mut c = b.cursor();
+            assert_eq!(c.first()?, Some((&b"bar"[..], Some(&b"b"[..]))));
+            assert_eq!(c.next()?, Some((&b"baz"[..], Some(&b"b"[..]))));
+            assert_eq!(c.next()?, Some((&b"foo"[..], Some(&b"f"[..]))));
+            assert_eq!(c.next()?, Some((&b"qux"[..], Some(&b"q"[..]))));
+            assert_eq!(c.next()?, Some((&b"sub"[..], None)));
+            assert_eq!(c.next()?, None);
+            assert_eq!(c.prev()?, Some((&b"sub"[..], None)));
+
+            assert_eq!(c.last()?, Some((&b"sub"[..], None)));
+            assert_eq!(c.prev()?, Some((&b"qux"[..], Some(&b"q"[..]))));
+
+            assert_eq!(c.seek(b"bb")?, Some((&b"foo"[..], Some(&b"f"[..]))));
+            assert_eq!(c.seek(b"baz")?, Some((&b"baz"[..], Some(&b"b"[..]))));
+            assert_eq!(c.prev()?, Some((&b"bar"[..], Some(&b"b"[..]))));
+            assert_eq!(c.prev()?, None);
+            assert_eq!(c.seek(b"zzz")?, None);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_cursor_empty_bucket_and_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db")).unwrap();
+
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            let mut c = b.cursor();
+            assert_eq!(c.first()?, None);
+            assert_eq!(c.last()?, None);
+            assert_eq!(c.seek(b"foo")?, None);
+
+            b.put(b"a", b"1")?;
+            b.put(b"b", b"2")?;
+            b.create_bucket(b"c")?;
+            let mut c = b.cursor();
+            c.seek(b"a")?;
+            c.delete()?;
+            assert_eq!(c.first()?, Some((&b"b"[..], Some(&b"2"[..]))));
+            c.last()?;
+            assert!(matches!(c.delete(), Err(Error::IncompatibleValue)));
+            Ok(())
+        })
+        .unwrap();
+
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?.unwrap();
+            assert_eq!(b.get(b"a")?, None);
+            assert!(matches!(b.cursor().delete(), Err(Error::TxNotWritable)));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_cursor_delete_while_iterating() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db")).unwrap();
+
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..1000u32 {
+                b.put(&i.to_be_bytes(), &[0; 32])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        db.update(|tx| {
+            let b = tx.bucket(b"widgets")?.unwrap();
+            let mut c = b.cursor();
+            let mut deleted = 0u32;
+            let mut item = c.first()?;
+            while let Some((key, _)) = item {
+                assert_eq!(key, deleted.to_be_bytes());
+                c.delete()?;
+                deleted += 1;
+                item = c.next()?;
+            }
+            assert_eq!(deleted, 1000);
+            assert_eq!(b.cursor().first()?, None);
+            Ok(())
+        })
+        .unwrap();
+
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?.unwrap();
+            assert_eq!(b.cursor().first()?, None);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_cursor_skips_emptied_leaves_backward() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db")).unwrap();
+
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..1000u32 {
+                b.put(&i.to_be_bytes(), &[0; 32])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        // Empty the last leaves and a few in the middle, within the same
+        // transaction, then walk backward over them.
+        db.update(|tx| {
+            let b = tx.bucket(b"widgets")?.unwrap();
+            for i in (300..400).chain(800..1000u32) {
+                b.delete(&i.to_be_bytes())?;
+            }
+            let mut c = b.cursor();
+            let mut keys = Vec::new();
+            let mut item = c.last()?;
+            while let Some((key, _)) = item {
+                keys.push(key);
+                item = c.prev()?;
+            }
+            let expected: Vec<_> = (0..300).chain(400..800u32).rev().collect();
+            assert_eq!(
+                keys,
+                expected.iter().map(|i| i.to_be_bytes()).collect::<Vec<_>>()
+            );
+            assert_eq!(b.iter().count(), 700);
+            Ok(())
+        })
+        .unwrap();
+    }
+}
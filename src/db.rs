@@ -0,0 +1,1336 @@
+#[cfg(test)]
+use std::cell::Cell;
+use std::fs::{File, OpenOptions, TryLockError};
+#[cfg(test)]
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use memmap2::{Mmap, MmapOptions};
+
+use crate::bucket::{BucketHeader, BUCKET_HEADER_SIZE};
+use crate::error::{Error, Result};
+use crate::freelist::Freelist;
+use crate::page::{
+    CorruptPage, Meta, Page, PageId, BUCKET_LEAF_FLAG, FREELIST_PAGE_FLAG, LEAF_PAGE_FLAG, MAGIC,
+    META_PAGE_FLAG, META_SIZE, NO_FREELIST, PAGE_HEADER_SIZE, VERSION,
+};
+use crate::transaction::{Tx, TxId, TxStats};
+
+pub(crate) const DEFAULT_PAGE_SIZE: usize = 4096;
+pub(crate) const MIN_PAGE_SIZE: usize = 1024;
+pub(crate) const MAX_PAGE_SIZE: usize = 64 * 1024;
+
+const MIN_MMAP_SIZE: usize = 1 << 15;
+const MAX_MMAP_STEP: usize = 1 << 30;
+const MAX_MAP_SIZE: usize = 0xFFFF_FFFF_FFFF;
+
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+#[cfg(test)]
+thread_local! {
+    /// Makes `remap` fail on the current thread, to test how commits cope.
+    pub(crate) static FAIL_REMAP: Cell<bool> = const { Cell::new(false) };
+}
+
+/// How free pages are tracked in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FreelistType {
+    /// A sorted array of page ids. Allocating a run of pages scans the whole
+    /// array.
+    #[default]
+    Array,
+    /// Runs of free pages indexed by size and by their first and last page,
+    /// which keeps allocations fast in large files with many free pages.
+    HashMap,
+}
+
+/// Settings used when opening a database.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Page size of a newly created database, a power of two between 1 KiB
+    /// and 64 KiB. Existing databases keep the page size they were created
+    /// with.
+    pub page_size: Option<usize>,
+    pub freelist_type: FreelistType,
+    /// Skips writing the freelist on commit. It is rebuilt by scanning the
+    /// whole file when the database is opened instead, which makes commits
+    /// cheaper and opening slower.
+    pub no_freelist_sync: bool,
+    /// Skips flushing the file to disk on commit. A crash can then lose or
+    /// corrupt recent commits; meant for bulk loads and tests.
+    pub no_sync: bool,
+    /// Opens the file read-only and under a shared lock, so that several
+    /// processes can read it at once. Writable transactions fail.
+    pub read_only: bool,
+    /// How long to wait for the file lock held by another process before
+    /// giving up with `Error::Timeout`. Waits forever if unset.
+    pub lock_timeout: Option<Duration>,
+}
+
+/// Counters of database activity. See `DB::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Free pages ready to be reused.
+    pub free_pages: usize,
+    /// Pages freed by transactions that open read transactions may still see.
+    pub pending_pages: usize,
+    /// Bytes taken up by free pages.
+    pub free_alloc: usize,
+    /// Bytes the freelist takes up on disk.
+    pub freelist_in_use: usize,
+    /// Read transactions started so far, and those still open.
+    pub txs: u64,
+    pub open_txs: usize,
+    /// Counters summed over every closed transaction.
+    pub tx_stats: TxStats,
+}
+
+impl Stats {
+    /// Returns the activity since the `other` snapshot was taken. Gauges,
+    /// such as the number of free pages, are the current values. Counters
+    /// that went down come out as zero, as in `TxStats::sub`.
+    pub fn sub(&self, other: &Stats) -> Stats {
+        Stats {
+            txs: self.txs.saturating_sub(other.txs),
+            tx_stats: self.tx_stats.sub(&other.tx_stats),
+            ..*self
+        }
+    }
+}
+
+pub struct DB {
+    path: PathBuf,
+    file: File,
+    page_size: usize,
+    meta: Mutex<Meta>,
+    mmap: RwLock<Mmap>,
+    rw_lock: Mutex<()>,
+    read_only: bool,
+    no_freelist_sync: bool,
+    no_sync: bool,
+    freelist: Mutex<Freelist>,
+    /// Ids of the open read-only transactions.
+    txs: Mutex<Vec<TxId>>,
+    stats: Mutex<Stats>,
+}
+
+/// Read access to the mapped data file. Remapping waits until every
+/// `PageSource` is dropped, so pages handed out stay valid while it is held.
+pub(crate) struct PageSource<'a> {
+    mmap: RwLockReadGuard<'a, Mmap>,
+    page_size: usize,
+}
+
+impl DB {
+    /// Opens the database at `path`, creating and initializing the file if it
+    /// does not exist yet.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<DB> {
+        DB::open_with_options(path, Options::default())
+    }
+
+    /// Like `open`, with non-default settings. The file is locked for as long
+    /// as the database stays open: exclusively, or shared if it is opened
+    /// read-only.
+    pub fn open_with_options<P: AsRef<Path>>(path: P, options: Options) -> Result<DB> {
+        let page_size = options.page_size.unwrap_or(DEFAULT_PAGE_SIZE);
+        if !page_size.is_power_of_two() || !(MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size) {
+            return Err(Error::InvalidPageSize);
+        }
+
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!options.read_only)
+            .create(!options.read_only)
+            .truncate(false)
+            .open(&path)?;
+        lock(&file, !options.read_only, options.lock_timeout)?;
+        if file.metadata()?.len() == 0 {
+            if options.read_only {
+                return Err(Error::Invalid);
+            }
+            init(&file, page_size)?;
+        }
+
+        let (page_size, meta) = read_meta(&file)?;
+        let len = file.metadata()?.len() as usize;
+        let mmap = if options.read_only {
+            // The file cannot be grown, so only what it holds is mapped.
+            unsafe { MmapOptions::new().len(len).map(&file)? }
+        } else {
+            map(&file, mmap_size(len, page_size)?)?
+        };
+        let db = DB {
+            path,
+            file,
+            page_size,
+            meta: Mutex::new(meta),
+            mmap: RwLock::new(mmap),
+            rw_lock: Mutex::new(()),
+            read_only: options.read_only,
+            no_freelist_sync: options.no_freelist_sync,
+            no_sync: options.no_sync,
+            freelist: Mutex::new(Freelist::new(options.freelist_type)),
+            txs: Mutex::new(Vec::new()),
+            stats: Mutex::new(Stats::default()),
+        };
+
+        // Read-only handles never allocate pages.
+        if db.read_only {
+            return Ok(db);
+        }
+        if meta.freelist == NO_FREELIST {
+            let ids = free_pages(&db.pages(), &meta)?;
+            db.freelist().read_ids(ids);
+            // Write the rebuilt freelist out if it is supposed to be synced.
+            if !db.no_freelist_sync {
+                db.begin(true)?.commit()?;
+            }
+        } else {
+            db.freelist().read(&db.pages().page(meta.freelist)?)?;
+        }
+        Ok(db)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Starts a new transaction. Read-only transactions see the database as of
+    /// the last commit when they started; only one writable transaction can be
+    /// open at a time and `begin(true)` blocks until the previous one is done.
+    ///
+    /// A read-only transaction blocks the mapping from growing, so a thread
+    /// holding one must not also commit a writable transaction.
+    pub fn begin(&self, writable: bool) -> Result<Tx<'_>> {
+        if writable && self.read_only {
+            return Err(Error::DatabaseReadOnly);
+        }
+        let writer = if writable {
+            Some(self.rw_lock.lock().unwrap_or_else(PoisonError::into_inner))
+        } else {
+            None
+        };
+        let pages = self.pages();
+        let meta = self.meta.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut txs = self.txs.lock().unwrap_or_else(PoisonError::into_inner);
+        if writable {
+            // Pages freed before the oldest open read transaction started can
+            // no longer be seen by anyone.
+            let min = txs.iter().copied().min().unwrap_or(TxId::MAX);
+            if min > 0 {
+                self.freelist().release(min - 1);
+            }
+        } else {
+            txs.push(meta.tx_id);
+            self.stats
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .txs += 1;
+        }
+        Ok(Tx::new(self, *meta, pages, writer))
+    }
+
+    /// Runs `f` inside a read-only transaction.
+    pub fn view<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Tx) -> Result<T>,
+    {
+        let tx = self.begin(false)?;
+        let result = f(&tx);
+        tx.rollback()?;
+        result
+    }
+
+    /// Runs `f` inside a writable transaction, committing it if `f` succeeds.
+    /// The transaction is rolled back if `f` returns an error or panics.
+    pub fn update<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Tx) -> Result<T>,
+    {
+        let tx = self.begin(true)?;
+        match f(&tx) {
+            Ok(value) => {
+                tx.commit()?;
+                Ok(value)
+            }
+            Err(err) => {
+                tx.rollback()?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Returns the state of the freelist, along with counters summed over
+    /// every closed transaction.
+    pub fn stats(&self) -> Stats {
+        let mut stats = *self.stats.lock().unwrap_or_else(PoisonError::into_inner);
+        let freelist = self.freelist();
+        stats.free_pages = freelist.free_count();
+        stats.pending_pages = freelist.pending_count();
+        stats.free_alloc = stats.free_pages * self.page_size;
+        stats.freelist_in_use = freelist.size();
+        drop(freelist);
+        stats.open_txs = self
+            .txs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len();
+        stats
+    }
+
+    pub(crate) fn add_tx_stats(&self, tx_stats: &TxStats) {
+        let mut stats = self.stats.lock().unwrap_or_else(PoisonError::into_inner);
+        stats.tx_stats.add(tx_stats);
+    }
+
+    pub(crate) fn no_freelist_sync(&self) -> bool {
+        self.no_freelist_sync
+    }
+
+    pub(crate) fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Returns the meta written by the last commit.
+    pub fn meta(&self) -> Meta {
+        *self.meta.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn freelist(&self) -> MutexGuard<'_, Freelist> {
+        self.freelist.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Unregisters a read-only transaction that is closing.
+    pub(crate) fn remove_tx(&self, tx_id: TxId) {
+        let mut txs = self.txs.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(idx) = txs.iter().position(|&id| id == tx_id) {
+            txs.swap_remove(idx);
+        }
+    }
+
+    pub(crate) fn set_meta(&self, meta: Meta) {
+        *self.meta.lock().unwrap_or_else(PoisonError::into_inner) = meta;
+    }
+
+    /// Writes `meta` to the meta page selected by its transaction id, so the
+    /// previous meta stays intact until the new one is fully on disk.
+    pub(crate) fn write_meta(&self, meta: &mut Meta) -> Result<()> {
+        let page_id = meta.tx_id % 2;
+        let mut buf = vec![0u8; self.page_size];
+        Page::write_header(&mut buf, page_id, META_PAGE_FLAG, 0, 0);
+        meta.write(&mut buf);
+        self.file
+            .write_all_at(&buf, page_id * self.page_size as u64)?;
+        self.sync()
+    }
+
+    /// Flushes written data to disk, unless the database was opened with
+    /// `no_sync`.
+    pub(crate) fn sync(&self) -> Result<()> {
+        if !self.no_sync {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn pages(&self) -> PageSource<'_> {
+        PageSource {
+            mmap: self.mmap.read().unwrap_or_else(PoisonError::into_inner),
+            page_size: self.page_size,
+        }
+    }
+
+    /// Grows the mapping so it covers at least `min_size` bytes, extending the
+    /// file if needed. Blocks until every outstanding `PageSource` is released.
+    pub(crate) fn remap(&self, min_size: usize) -> Result<()> {
+        if self
+            .mmap
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+            >= min_size
+        {
+            return Ok(());
+        }
+        let mut mmap = self.mmap.write().unwrap_or_else(PoisonError::into_inner);
+        if mmap.len() >= min_size {
+            return Ok(());
+        }
+        #[cfg(test)]
+        if FAIL_REMAP.get() {
+            return Err(io::Error::other("remap failed").into());
+        }
+        let size = mmap_size(min_size, self.page_size)?;
+        *mmap = map(&self.file, size)?;
+        Ok(())
+    }
+}
+
+impl PageSource<'_> {
+    /// Returns the page stored at `page_id`, including its overflow pages.
+    pub(crate) fn page(&self, page_id: PageId) -> std::result::Result<Page<'_>, CorruptPage> {
+        let out_of_range = CorruptPage {
+            page_id,
+            reason: "page out of range",
+        };
+        let offset = usize::try_from(page_id)
+            .ok()
+            .and_then(|page_id| page_id.checked_mul(self.page_size))
+            .ok_or(out_of_range.clone())?;
+        let buf = self
+            .bytes(offset, self.page_size)
+            .ok_or(out_of_range.clone())?;
+
+        let page = Page::from_bytes(buf)?;
+        if page.id() != page_id {
+            return Err(page.corrupt("unexpected page id"));
+        }
+        let len = (page.overflow() as usize + 1)
+            .checked_mul(self.page_size)
+            .ok_or(out_of_range.clone())?;
+        Page::from_bytes(self.bytes(offset, len).ok_or(out_of_range)?)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.mmap.len()
+    }
+
+    /// Returns `len` bytes of the mapping starting at `offset`.
+    pub(crate) fn bytes(&self, offset: usize, len: usize) -> Option<&[u8]> {
+        self.mmap.get(offset..offset.checked_add(len)?)
+    }
+}
+
+/// Returns the ids below the high-water mark of `meta` that are not used by
+/// the metas, the freelist or any bucket, which is the freelist when it is
+/// not written to disk.
+pub(crate) fn free_pages(pages: &PageSource, meta: &Meta) -> Result<Vec<PageId>> {
+    if meta.page_id < 2 || meta.page_id > (pages.len() / pages.page_size) as PageId {
+        return Err(Error::Corrupt {
+            page_id: meta.tx_id % 2,
+            reason: "high-water mark out of range",
+        });
+    }
+    let mut reachable = vec![false; meta.page_id as usize];
+    reachable[..2].fill(true);
+    if meta.freelist != NO_FREELIST {
+        mark_reachable(pages, meta.freelist, &mut reachable)?;
+    }
+    mark_bucket(pages, meta.root, &mut reachable)?;
+    Ok((2..meta.page_id)
+        .filter(|&id| !reachable[id as usize])
+        .collect())
+}
+
+/// Marks the page and its overflow pages as reachable, failing if any of
+/// them already was.
+fn mark_reachable<'a>(
+    pages: &'a PageSource,
+    page_id: PageId,
+    reachable: &mut [bool],
+) -> Result<Page<'a>> {
+    let page = pages.page(page_id)?;
+    for id in page_id..=page_id + page.overflow() as PageId {
+        match reachable.get_mut(id as usize) {
+            Some(seen) if !*seen => *seen = true,
+            Some(_) => return Err(page.corrupt("page reachable twice").into()),
+            None => return Err(page.corrupt("page above the high-water mark").into()),
+        }
+    }
+    Ok(page)
+}
+
+/// Marks every page of the bucket tree rooted at `page_id` as reachable,
+/// along with the pages of its nested buckets.
+fn mark_bucket(pages: &PageSource, page_id: PageId, reachable: &mut [bool]) -> Result<()> {
+    let page = mark_reachable(pages, page_id, reachable)?;
+    mark_children(pages, &page, reachable)
+}
+
+fn mark_children(pages: &PageSource, page: &Page, reachable: &mut [bool]) -> Result<()> {
+    if page.is_branch() {
+        for elem in page.branch_page_elements() {
+            mark_bucket(pages, elem?.page_id(), reachable)?;
+        }
+        return Ok(());
+    }
+    for elem in page.leaf_page_elements() {
+        let elem = elem?;
+        if elem.flag() & BUCKET_LEAF_FLAG as u32 == 0 {
+            continue;
+        }
+        let header = BucketHeader::read(elem.value())
+            .ok_or_else(|| page.corrupt("bucket header out of bounds"))?;
+        if header.root != 0 {
+            mark_bucket(pages, header.root, reachable)?;
+        } else {
+            let inline = Page::from_bytes(&elem.value()[BUCKET_HEADER_SIZE..])?;
+            mark_children(pages, &inline, reachable)?;
+        }
+    }
+    Ok(())
+}
+
+/// Locks the file, exclusively or shared. With a timeout the lock is retried
+/// until it expires; without one this blocks until the lock is available.
+fn lock(file: &File, exclusive: bool, timeout: Option<Duration>) -> Result<()> {
+    let Some(timeout) = timeout else {
+        if exclusive {
+            file.lock()?;
+        } else {
+            file.lock_shared()?;
+        }
+        return Ok(());
+    };
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let result = if exclusive {
+            file.try_lock()
+        } else {
+            file.try_lock_shared()
+        };
+        match result {
+            Ok(()) => return Ok(()),
+            Err(TryLockError::WouldBlock) => {}
+            Err(TryLockError::Error(err)) => return Err(err.into()),
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::Timeout);
+        }
+        thread::sleep(LOCK_RETRY_INTERVAL.min(deadline - now));
+    }
+}
+
+/// Returns the size of the mapping needed to hold `size` bytes: powers of two
+/// from 32 KiB up to 1 GiB, then whole 1 GiB steps.
+fn mmap_size(size: usize, page_size: usize) -> Result<usize> {
+    for shift in MIN_MMAP_SIZE.trailing_zeros()..=MAX_MMAP_STEP.trailing_zeros() {
+        if size <= 1 << shift {
+            return Ok(1 << shift);
+        }
+    }
+    if size > MAX_MAP_SIZE {
+        return Err(Error::MmapTooLarge);
+    }
+
+    let mut size = size.next_multiple_of(MAX_MMAP_STEP);
+    size = size.next_multiple_of(page_size);
+    Ok(size.min(MAX_MAP_SIZE))
+}
+
+/// Maps `size` bytes of `file`, first growing the file so every mapped page is
+/// backed by it.
+fn map(file: &File, size: usize) -> Result<Mmap> {
+    if file.metadata()?.len() < size as u64 {
+        file.set_len(size as u64)?;
+    }
+    Ok(unsafe { MmapOptions::new().len(size).map(file)? })
+}
+
+/// Writes the initial layout of a new database: two meta pages, an empty
+/// freelist page and an empty leaf page for the root bucket.
+fn init(file: &File, page_size: usize) -> Result<()> {
+    let mut buf = vec![0u8; page_size * 4];
+
+    for (i, page) in buf.chunks_mut(page_size).take(2).enumerate() {
+        Page::write_header(page, i as PageId, META_PAGE_FLAG, 0, 0);
+        let mut meta = Meta {
+            magic: MAGIC,
+            version: VERSION,
+            page_size: page_size as u32,
+            flags: 0,
+            root: 3,
+            freelist: 2,
+            page_id: 4,
+            tx_id: i as u64,
+            checksum: 0,
+        };
+        meta.write(page);
+    }
+
+    Page::write_header(&mut buf[page_size * 2..], 2, FREELIST_PAGE_FLAG, 0, 0);
+    Page::write_header(&mut buf[page_size * 3..], 3, LEAF_PAGE_FLAG, 0, 0);
+
+    file.write_all_at(&buf, 0)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Reads both meta pages and returns the page size along with the newest valid
+/// meta. The page size is taken from the first meta page; when that one is
+/// unreadable, the second meta page is searched for at every supported page
+/// size instead.
+fn read_meta(file: &File) -> Result<(usize, Meta)> {
+    let meta0 = read_meta_at(file, 0);
+    let page_size = match meta0 {
+        Ok(ref meta) => meta.page_size as usize,
+        Err(_) => match detect_page_size(file) {
+            Some(page_size) => page_size,
+            None => return meta0.map(|meta| (meta.page_size as usize, meta)),
+        },
+    };
+    let meta1 = read_meta_at(file, page_size as u64);
+
+    let meta = match (meta0, meta1) {
+        (Ok(meta0), Ok(meta1)) if meta1.tx_id > meta0.tx_id => meta1,
+        (Ok(meta0), _) => meta0,
+        (Err(_), Ok(meta1)) => meta1,
+        (Err(err), Err(_)) => return Err(err),
+    };
+    Ok((page_size, meta))
+}
+
+fn detect_page_size(file: &File) -> Option<usize> {
+    (MIN_PAGE_SIZE.trailing_zeros()..=MAX_PAGE_SIZE.trailing_zeros())
+        .map(|shift| 1usize << shift)
+        .find(|&page_size| match read_meta_at(file, page_size as u64) {
+            Ok(meta) => meta.page_size as usize == page_size,
+            Err(_) => false,
+        })
+}
+
+fn read_meta_at(file: &File, offset: u64) -> Result<Meta> {
+    let mut buf = [0u8; PAGE_HEADER_SIZE + META_SIZE];
+    file.read_exact_at(&mut buf, offset)?;
+    let meta = Meta::read(&buf);
+    meta.validate()?;
+    Ok(meta)
+}
+
+impl Meta {
+    fn validate(&self) -> Result<()> {
+        if self.magic != MAGIC {
+            return Err(Error::Invalid);
+        }
+        if self.version != VERSION {
+            return Err(Error::VersionMismatch);
+        }
+        if self.checksum != self.sum64() {
+            return Err(Error::Checksum);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::os::unix::fs::FileExt;
+    use std::time::Duration;
+
+    use crate::db::{mmap_size, FreelistType, Options, DB, DEFAULT_PAGE_SIZE, FAIL_REMAP};
+    use crate::error::{Error, Result};
+    use crate::page::{Meta, LEAF_PAGE_FLAG, META_PAGE_FLAG, NO_FREELIST, PAGE_HEADER_SIZE};
+    use crate::transaction::{TxId, TxStats};
+
+    #[test]
+    fn test_open_creates_and_reopens() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+
+        let db = DB::open(&path).unwrap();
+        assert_eq!(db.page_size(), DEFAULT_PAGE_SIZE);
+        drop(db);
+        assert!(fs::metadata(&path).unwrap().len() >= 4 * DEFAULT_PAGE_SIZE as u64);
+
+        let db = DB::open(&path).unwrap();
+        assert_eq!(db.page_size(), DEFAULT_PAGE_SIZE);
+        assert_eq!(db.meta().root, 3);
+        assert_eq!(db.meta().page_id, 4);
+    }
+
+    #[test]
+    fn test_open_falls_back_to_valid_meta() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+
+        let db = DB::open(&path).unwrap();
+        let mut meta = db.meta();
+        meta.tx_id = 2;
+        db.write_meta(&mut meta).unwrap();
+        drop(db);
+        assert_eq!(DB::open(&path).unwrap().meta().tx_id, 2);
+
+        // Tear the newest meta page: the previous one is picked up instead.
+        let mut data = fs::read(&path).unwrap();
+        data[PAGE_HEADER_SIZE + 20] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+        assert_eq!(DB::open(&path).unwrap().meta().tx_id, 1);
+
+        // Page size is still found through the second meta page when the first
+        // one is garbage.
+        data[..PAGE_HEADER_SIZE + 8].fill(0xab);
+        fs::write(&path, &data).unwrap();
+        let db = DB::open(&path).unwrap();
+        assert_eq!(db.page_size(), DEFAULT_PAGE_SIZE);
+        assert_eq!(db.meta().tx_id, 1);
+        drop(db);
+
+        let offset = DEFAULT_PAGE_SIZE + PAGE_HEADER_SIZE + 40;
+        data[offset] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+        assert!(DB::open(&path).is_err());
+    }
+
+    #[test]
+    fn test_file_locking() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let read_only = Options {
+            read_only: true,
+            lock_timeout: Some(Duration::from_millis(100)),
+            ..Options::default()
+        };
+        let read_write = Options {
+            lock_timeout: Some(Duration::from_millis(100)),
+            ..Options::default()
+        };
+        assert!(DB::open_with_options(&path, read_only.clone()).is_err());
+
+        let db = DB::open(&path).unwrap();
+        db.update(|tx| tx.create_bucket(b"widgets")?.put(b"foo", b"bar"))
+            .unwrap();
+        assert!(matches!(
+            DB::open_with_options(&path, read_write.clone()),
+            Err(Error::Timeout)
+        ));
+        assert!(matches!(
+            DB::open_with_options(&path, read_only.clone()),
+            Err(Error::Timeout)
+        ));
+        drop(db);
+
+        // Any number of read-only handles can share the file, but they keep
+        // writers out.
+        let a = DB::open_with_options(&path, read_only.clone()).unwrap();
+        let b = DB::open_with_options(&path, read_only).unwrap();
+        assert!(a.read_only());
+        assert!(matches!(a.begin(true), Err(Error::DatabaseReadOnly)));
+        b.view(|tx| {
+            assert_eq!(
+                tx.bucket(b"widgets")?.unwrap().get(b"foo")?,
+                Some(&b"bar"[..])
+            );
+            Ok(())
+        })
+        .unwrap();
+        assert!(matches!(
+            DB::open_with_options(&path, read_write.clone()),
+            Err(Error::Timeout)
+        ));
+        drop((a, b));
+        DB::open_with_options(&path, read_write).unwrap();
+    }
+
+    #[test]
+    fn test_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db")).unwrap();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..1000u32 {
+                b.put(&i.to_be_bytes(), &[0; 100])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        let before = db.stats();
+        assert!(before.tx_stats.pages > 0);
+        assert_eq!(
+            before.tx_stats.page_alloc,
+            before.tx_stats.pages * db.page_size() as u64
+        );
+        assert!(before.tx_stats.splits > 0);
+        assert!(before.tx_stats.spills > before.tx_stats.splits);
+        assert!(before.tx_stats.writes >= 2);
+        // Every allocated page is written, followed by the meta page.
+        assert_eq!(
+            before.tx_stats.write_bytes,
+            before.tx_stats.page_alloc + db.page_size() as u64
+        );
+
+        db.update(|tx| {
+            let b = tx.bucket(b"widgets")?.unwrap();
+            for i in 0..1000u32 {
+                b.delete(&i.to_be_bytes())?;
+            }
+            assert!(tx.stats().nodes > 0);
+            Ok(())
+        })
+        .unwrap();
+        let tx = db.begin(false).unwrap();
+        let stats = db.stats().sub(&before);
+        assert_eq!(stats.txs, 1);
+        assert_eq!(stats.open_txs, 1);
+        assert!(stats.tx_stats.rebalances > 0);
+        assert!(stats.free_pages + stats.pending_pages > 0);
+        assert_eq!(stats.free_alloc, stats.free_pages * db.page_size());
+        drop(tx);
+        assert_eq!(db.stats().open_txs, 0);
+
+        // Snapshots passed the wrong way round do not underflow.
+        let stats = before.sub(&db.stats());
+        assert_eq!(stats.txs, 0);
+        assert_eq!(stats.tx_stats, TxStats::default());
+    }
+
+    #[test]
+    fn test_write_to() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db")).unwrap();
+        let put = |key: &[u8]| {
+            db.update(|tx| {
+                tx.create_bucket_if_not_exists(b"widgets")?
+                    .put(key, &[0; 1000])
+            })
+        };
+        for i in 0..100u32 {
+            put(&i.to_be_bytes()).unwrap();
+        }
+
+        // Writers carry on while the copy is taken, as long as the mapping
+        // does not need to grow.
+        db.remap(1 << 20).unwrap();
+        let tx = db.begin(false).unwrap();
+        put(b"after").unwrap();
+        let mut buf = Vec::new();
+        let len = tx.write_to(&mut buf).unwrap();
+        assert_eq!(len, buf.len() as u64);
+        let copy = dir.path().join("copy");
+        tx.copy_file(&copy).unwrap();
+        drop(tx);
+        assert_eq!(fs::read(&copy).unwrap(), buf);
+
+        let db = DB::open(&copy).unwrap();
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?.unwrap();
+            assert_eq!(b.iter().count(), 100);
+            assert_eq!(b.get(b"after")?, None);
+            assert_eq!(tx.check().count(), 0);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_page_infos() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = DB::open(&path).unwrap();
+        db.update(|tx| tx.create_bucket(b"widgets")?.put(b"foo", &[0; 10_000]))
+            .unwrap();
+        db.update(|tx| tx.bucket(b"widgets")?.unwrap().put(b"bar", b"baz"))
+            .unwrap();
+
+        let infos = db.view(|tx| tx.page_infos()).unwrap();
+        let kinds: Vec<_> = infos.iter().map(|info| info.kind).collect();
+        assert_eq!(&kinds[..2], ["meta", "meta"]);
+        assert!(kinds.contains(&"free"));
+        assert_eq!(kinds.iter().filter(|&&kind| kind == "freelist").count(), 1);
+        let large = infos.iter().find(|info| info.overflow > 0).unwrap();
+        assert_eq!((large.kind, large.count, large.overflow), ("leaf", 2, 2));
+
+        // Every page below the high-water mark is listed exactly once.
+        let mut next = 0;
+        for info in &infos {
+            assert_eq!(info.id, next);
+            next += info.overflow as u64 + 1;
+        }
+        assert_eq!(next, db.meta().page_id);
+        let large = large.id;
+        drop(db);
+
+        // A damaged page is listed as such, and so are the overflow pages
+        // that nothing claims any more.
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(&0u64.to_le_bytes(), large * DEFAULT_PAGE_SIZE as u64)
+            .unwrap();
+        drop(file);
+        let options = Options {
+            read_only: true,
+            ..Options::default()
+        };
+        let db = DB::open_with_options(&path, options).unwrap();
+        let infos = db.view(|tx| tx.page_infos()).unwrap();
+        let corrupt: Vec<_> = infos
+            .iter()
+            .filter(|info| info.kind == "corrupt")
+            .map(|info| info.id)
+            .collect();
+        assert_eq!(corrupt, [large, large + 1, large + 2]);
+        assert_eq!(infos.last().unwrap().id + 1, db.meta().page_id);
+    }
+
+    #[test]
+    fn test_transactions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = DB::open(&path).unwrap();
+
+        let tx = db.begin(false).unwrap();
+        assert_eq!(tx.id(), 1);
+        assert!(!tx.writable());
+        assert!(matches!(
+            db.begin(false).unwrap().commit(),
+            Err(Error::TxNotWritable)
+        ));
+
+        let tx2 = db.begin(true).unwrap();
+        assert_eq!(tx2.id(), 2);
+        tx2.commit().unwrap();
+
+        // Read transactions keep the snapshot they started with.
+        assert_eq!(tx.id(), 1);
+        assert_eq!(db.begin(false).unwrap().id(), 2);
+        drop(tx);
+
+        db.begin(true).unwrap().rollback().unwrap();
+        let result: Result<()> = db.update(|_| Err(Error::TxNotWritable));
+        assert!(result.is_err());
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            db.update(|_| -> Result<()> { panic!("boom") })
+        }));
+        assert!(result.is_err());
+        assert_eq!(db.view(|tx| Ok(tx.id())).unwrap(), 2);
+
+        assert_eq!(db.update(|tx| Ok(tx.id())).unwrap(), 3);
+        drop(db);
+        assert_eq!(DB::open(&path).unwrap().meta().tx_id, 3);
+    }
+
+    #[test]
+    fn test_open_with_page_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        for page_size in [0, 512, 3000, 128 * 1024] {
+            let options = Options {
+                page_size: Some(page_size),
+                ..Options::default()
+            };
+            assert!(matches!(
+                DB::open_with_options(&path, options),
+                Err(Error::InvalidPageSize)
+            ));
+        }
+        assert!(!path.exists());
+
+        let options = Options {
+            page_size: Some(16 * 1024),
+            ..Options::default()
+        };
+        let db = DB::open_with_options(&path, options).unwrap();
+        assert_eq!(db.page_size(), 16 * 1024);
+        db.update(|tx| tx.create_bucket(b"widgets")?.put(b"foo", b"bar"))
+            .unwrap();
+        drop(db);
+
+        // The page size of an existing file wins over the default.
+        let db = DB::open(&path).unwrap();
+        assert_eq!(db.page_size(), 16 * 1024);
+        db.view(|tx| {
+            assert_eq!(
+                tx.bucket(b"widgets")?.unwrap().get(b"foo")?,
+                Some(&b"bar"[..])
+            );
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_freed_pages_are_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = DB::open(&path).unwrap();
+
+        for i in 0..100u32 {
+            db.update(|tx| {
+                let b = tx.create_bucket_if_not_exists(b"widgets")?;
+                b.put(b"counter", &i.to_le_bytes())
+            })
+            .unwrap();
+        }
+        assert!(db.meta().page_id < 10);
+
+        db.update(|tx| {
+            let b = tx.create_bucket(b"gadgets")?;
+            for i in 0..1000u32 {
+                b.put(&i.to_be_bytes(), &[0; 100])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        let page_id = db.meta().page_id;
+        db.update(|tx| tx.delete_bucket(b"gadgets")).unwrap();
+        drop(db);
+
+        // The pages of the deleted bucket survive a reopen as free pages.
+        let db = DB::open(&path).unwrap();
+        assert!(db.freelist().count() > 25);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"gadgets")?;
+            for i in 0..1000u32 {
+                b.put(&i.to_be_bytes(), &[0; 100])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert!(db.meta().page_id <= page_id + 1);
+    }
+
+    #[test]
+    fn test_open_with_hashmap_freelist() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            freelist_type: FreelistType::HashMap,
+            ..Options::default()
+        };
+        let db = DB::open_with_options(dir.path().join("db"), options).unwrap();
+        for i in 0..100u32 {
+            db.update(|tx| {
+                let b = tx.create_bucket_if_not_exists(b"widgets")?;
+                b.put(&i.to_be_bytes(), &[0; 500])
+            })
+            .unwrap();
+        }
+        let page_id = db.meta().page_id;
+        db.update(|tx| tx.delete_bucket(b"widgets")).unwrap();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..100u32 {
+                b.put(&i.to_be_bytes(), &[0; 500])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert!(db.meta().page_id <= page_id);
+    }
+
+    #[test]
+    fn test_no_freelist_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let options = Options {
+            no_freelist_sync: true,
+            ..Options::default()
+        };
+        let db = DB::open_with_options(&path, options.clone()).unwrap();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..1000u32 {
+                b.put(&i.to_be_bytes(), &[0; 100])?;
+            }
+            tx.create_bucket(b"gadgets")?.put(b"foo", b"bar")
+        })
+        .unwrap();
+        db.update(|tx| {
+            let b = tx.bucket(b"widgets")?.unwrap();
+            for i in 0..500u32 {
+                b.delete(&i.to_be_bytes())?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(db.meta().freelist, NO_FREELIST);
+        let free = db.freelist().all();
+        assert!(!free.is_empty());
+
+        // Rolling back rebuilds the freelist by scanning the file.
+        let tx = db.begin(true).unwrap();
+        tx.bucket(b"widgets")
+            .unwrap()
+            .unwrap()
+            .put(b"baz", &[1; 5000])
+            .unwrap();
+        tx.root().spill().unwrap();
+        drop(tx);
+        assert_eq!(db.freelist().all(), free);
+        drop(db);
+
+        let db = DB::open_with_options(&path, options).unwrap();
+        assert_eq!(db.freelist().all(), free);
+        let tx = db.begin(true).unwrap();
+        assert_eq!(tx.check().count(), 0);
+        drop(tx);
+        drop(db);
+
+        // Opening without the option writes the freelist out again.
+        let db = DB::open(&path).unwrap();
+        assert_ne!(db.meta().freelist, NO_FREELIST);
+        assert_eq!(db.freelist().free_count() + 1, free.len());
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?.unwrap();
+            assert_eq!(b.iter().count(), 500);
+            assert_eq!(tx.check().count(), 0);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_no_freelist_sync_rejects_bad_high_water_mark() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let options = Options {
+            no_freelist_sync: true,
+            ..Options::default()
+        };
+        let db = DB::open_with_options(&path, options.clone()).unwrap();
+        db.update(|tx| tx.create_bucket(b"widgets").map(|_| ()))
+            .unwrap();
+        let tx_id = db.meta().tx_id;
+        drop(db);
+
+        let offset = tx_id % 2 * DEFAULT_PAGE_SIZE as u64;
+        for page_id in [0, 1, 1 << 40] {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .unwrap();
+            let mut buf = vec![0; DEFAULT_PAGE_SIZE];
+            file.read_exact_at(&mut buf, offset).unwrap();
+            let mut meta = Meta::read(&buf);
+            meta.page_id = page_id;
+            meta.write(&mut buf);
+            file.write_all_at(&buf, offset).unwrap();
+            drop(file);
+
+            assert!(matches!(
+                DB::open_with_options(&path, options.clone()),
+                Err(Error::Corrupt { page_id, .. }) if page_id == tx_id % 2
+            ));
+        }
+    }
+
+    #[test]
+    fn test_failed_remap_aborts_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = DB::open(&path).unwrap();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..10u32 {
+                b.put(&i.to_be_bytes(), &[0; 100])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        let meta = db.meta();
+
+        // The commit outgrows the initial mapping, which cannot be grown, so
+        // nothing is written and the freed pages are given back.
+        let update = |db: &DB| {
+            db.update(|tx| {
+                let b = tx.bucket(b"widgets")?.unwrap();
+                for i in 0..5u32 {
+                    b.delete(&i.to_be_bytes())?;
+                }
+                for i in 100..200u32 {
+                    b.put(&i.to_be_bytes(), &[1; 1000])?;
+                }
+                Ok(())
+            })
+        };
+        FAIL_REMAP.set(true);
+        let result = update(&db);
+        FAIL_REMAP.set(false);
+        assert!(matches!(result, Err(Error::Io(_))));
+        assert_eq!(db.meta().tx_id, meta.tx_id);
+        assert_eq!(db.meta().root, meta.root);
+
+        update(&db).unwrap();
+        let check = |db: &DB| {
+            db.view(|tx| {
+                assert_eq!(tx.bucket(b"widgets")?.unwrap().iter().count(), 105);
+                assert_eq!(tx.check().count(), 0);
+                Ok(())
+            })
+            .unwrap()
+        };
+        check(&db);
+        drop(db);
+        check(&DB::open(&path).unwrap());
+    }
+
+    #[test]
+    fn test_no_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let options = Options {
+            no_sync: true,
+            ..Options::default()
+        };
+        let db = DB::open_with_options(&path, options).unwrap();
+        for round in 0..5u32 {
+            // Deleting and rewriting keys spreads the dirty pages of each
+            // commit over runs of reused and newly allocated pages.
+            db.update(|tx| {
+                let b = tx.create_bucket_if_not_exists(b"widgets")?;
+                for i in (round..2000).step_by(3) {
+                    b.delete(&i.to_be_bytes())?;
+                    b.put(&i.to_be_bytes(), &[round as u8; 50])?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        }
+        drop(db);
+
+        let db = DB::open(&path).unwrap();
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?.unwrap();
+            assert_eq!(b.iter().count(), 2000);
+            assert_eq!(b.get(&1999u32.to_be_bytes())?, Some(&[4; 50][..]));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_pending_pages_wait_for_readers() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db")).unwrap();
+        let put = |value: &[u8]| {
+            db.update(|tx| {
+                tx.create_bucket_if_not_exists(b"widgets")?
+                    .put(b"foo", value)
+            })
+        };
+
+        // Grow the mapping up front, since it cannot be remapped while this
+        // thread holds a read transaction.
+        db.remap(1 << 20).unwrap();
+        put(b"bar").unwrap();
+        db.freelist().release(TxId::MAX);
+
+        let tx = db.begin(false).unwrap();
+        put(b"baz").unwrap();
+        put(b"qux").unwrap();
+        assert_eq!(db.freelist().free_count(), 0);
+        assert!(db.freelist().pending_count() > 0);
+        assert_eq!(
+            tx.bucket(b"widgets").unwrap().unwrap().get(b"foo").unwrap(),
+            Some(&b"bar"[..])
+        );
+        drop(tx);
+
+        put(b"quux").unwrap();
+        assert!(db.freelist().free_count() > 0);
+    }
+
+    #[test]
+    fn test_rollback_restores_freelist() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db")).unwrap();
+        db.update(|tx| tx.create_bucket(b"widgets")?.put(b"foo", b"bar"))
+            .unwrap();
+        db.update(|tx| tx.bucket(b"widgets")?.unwrap().put(b"foo", b"baz"))
+            .unwrap();
+
+        let all = db.freelist().all();
+        let tx = db.begin(true).unwrap();
+        let b = tx.create_bucket(b"gadgets").unwrap();
+        for i in 0..1000u32 {
+            b.put(&i.to_be_bytes(), &[0; 100]).unwrap();
+        }
+        tx.delete_bucket(b"widgets").unwrap();
+        // Spilling is what frees and allocates pages during a commit.
+        tx.root().spill().unwrap();
+        assert_ne!(db.freelist().all(), all);
+        drop(tx);
+        assert_eq!(db.freelist().all(), all);
+    }
+
+    #[test]
+    fn test_mmap_size() {
+        assert_eq!(mmap_size(0, 4096).unwrap(), 1 << 15);
+        assert_eq!(mmap_size(16 * 1024, 4096).unwrap(), 1 << 15);
+        assert_eq!(mmap_size((1 << 15) + 1, 4096).unwrap(), 1 << 16);
+        assert_eq!(mmap_size(1 << 30, 4096).unwrap(), 1 << 30);
+        assert_eq!(mmap_size((1 << 30) + 1, 4096).unwrap(), 2 << 30);
+        assert_eq!(mmap_size((5 << 30) - 1, 4096).unwrap(), 5 << 30);
+        assert!(mmap_size(1 << 50, 4096).is_err());
+    }
+
+    #[test]
+    fn test_remap_grows_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+
+        let db = DB::open(&path).unwrap();
+        {
+            let pages = db.pages();
+            assert_eq!(pages.len(), 1 << 15);
+            assert_eq!(pages.page(0).unwrap().flag(), META_PAGE_FLAG as u16);
+            assert_eq!(pages.page(3).unwrap().flag(), LEAF_PAGE_FLAG as u16);
+            assert!(pages.page(4).is_err());
+            assert!(pages.page(1 << 20).is_err());
+        }
+
+        db.remap(100_000).unwrap();
+        let pages = db.pages();
+        assert_eq!(pages.len(), 1 << 17);
+        assert_eq!(pages.page(1).unwrap().meta().unwrap().tx_id, 1);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 1 << 17);
+    }
+
+    #[test]
+    fn test_huge_page_id_is_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = DB::open(&path).unwrap();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..1000u32 {
+                b.put(&i.to_be_bytes(), &[0; 32])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        let root = db
+            .view(|tx| Ok(tx.bucket(b"widgets")?.unwrap().header().root))
+            .unwrap();
+        drop(db);
+
+        // Point the first child of the root at a page far past any file, whose
+        // offset does not fit in a usize.
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        let offset = root * DEFAULT_PAGE_SIZE as u64 + PAGE_HEADER_SIZE as u64 + 16;
+        file.write_all_at(&0x000F_FFFF_FFFF_FFFFu64.to_le_bytes(), offset)
+            .unwrap();
+        drop(file);
+
+        let db = DB::open(&path).unwrap();
+        let result = db.view(|tx| {
+            tx.bucket(b"widgets")?
+                .unwrap()
+                .get(&0u32.to_be_bytes())
+                .map(|_| ())
+        });
+        assert!(matches!(result, Err(Error::Corrupt { .. })));
+    }
+
+    #[test]
+    fn test_open_rejects_invalid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        fs::write(&path, vec![0xab; 8192]).unwrap();
+        assert!(matches!(DB::open(&path), Err(Error::Invalid)));
+    }
+}
@@ -0,0 +1,85 @@
+use std::{error, fmt, io};
+
+use crate::page::{CorruptPage, PageId};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The file is not a thrak database: both meta pages have the wrong magic
+    /// number or cannot be read.
+    Invalid,
+    /// The file was written by an incompatible version of the format.
+    VersionMismatch,
+    /// Both meta pages failed their checksum.
+    Checksum,
+    MmapTooLarge,
+    InvalidPageSize,
+    /// The file lock could not be taken within `Options::lock_timeout`.
+    Timeout,
+    DatabaseReadOnly,
+    /// The transaction was used after it was closed.
+    TxClosed,
+    TxNotWritable,
+    BucketNotFound,
+    BucketExists,
+    /// The key holds a value where a bucket was expected, or the other way
+    /// around.
+    IncompatibleValue,
+    KeyRequired,
+    KeyTooLarge,
+    ValueTooLarge,
+    Corrupt {
+        page_id: PageId,
+        reason: &'static str,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {err}"),
+            Error::Invalid => f.write_str("invalid database"),
+            Error::VersionMismatch => f.write_str("version mismatch"),
+            Error::Checksum => f.write_str("checksum error"),
+            Error::MmapTooLarge => f.write_str("mmap too large"),
+            Error::InvalidPageSize => f.write_str("invalid page size"),
+            Error::Timeout => f.write_str("timeout"),
+            Error::DatabaseReadOnly => f.write_str("database is in read-only mode"),
+            Error::TxClosed => f.write_str("tx closed"),
+            Error::TxNotWritable => f.write_str("tx not writable"),
+            Error::BucketNotFound => f.write_str("bucket not found"),
+            Error::BucketExists => f.write_str("bucket already exists"),
+            Error::IncompatibleValue => f.write_str("incompatible value"),
+            Error::KeyRequired => f.write_str("key required"),
+            Error::KeyTooLarge => f.write_str("key too large"),
+            Error::ValueTooLarge => f.write_str("value too large"),
+            Error::Corrupt { page_id, reason } => write!(f, "page {page_id} is corrupt: {reason}"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<CorruptPage> for Error {
+    fn from(err: CorruptPage) -> Self {
+        Error::Corrupt {
+            page_id: err.page_id,
+            reason: err.reason,
+        }
+    }
+}
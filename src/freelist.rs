@@ -0,0 +1,433 @@
+use std::collections::{BTreeMap, HashSet};
+
+use crate::db::FreelistType;
+use crate::freelist_hmap::SpanIndex;
+use crate::page::{
+    merge, merge_page_ids, CorruptPage, Page, PageId, FREELIST_PAGE_FLAG, PAGE_HEADER_SIZE,
+};
+use crate::transaction::TxId;
+
+/// Tracks the pages that can be reused by new allocations. Pages freed by a
+/// transaction stay pending until no open read transaction can still see
+/// them.
+#[derive(Clone)]
+pub(crate) struct Freelist {
+    ids: FreeIds,
+    /// Page ids freed by each transaction that are not released yet.
+    pending: BTreeMap<TxId, Vec<PageId>>,
+    /// Every free and pending page id, for fast lookups.
+    cache: HashSet<PageId>,
+}
+
+/// The released page ids, in the representation picked by `FreelistType`.
+#[derive(Clone)]
+enum FreeIds {
+    /// Sorted ids.
+    Array(Vec<PageId>),
+    HashMap(SpanIndex),
+}
+
+impl Freelist {
+    pub(crate) fn new(freelist_type: FreelistType) -> Freelist {
+        let ids = match freelist_type {
+            FreelistType::Array => FreeIds::Array(Vec::new()),
+            FreelistType::HashMap => FreeIds::HashMap(SpanIndex::default()),
+        };
+        Freelist {
+            ids,
+            pending: BTreeMap::new(),
+            cache: HashSet::new(),
+        }
+    }
+
+    /// Size of the freelist once written to a page.
+    pub(crate) fn size(&self) -> usize {
+        let mut count = self.count();
+        if count >= u16::MAX as usize {
+            // The real count is stored as the first element.
+            count += 1;
+        }
+        PAGE_HEADER_SIZE + count * 8
+    }
+
+    /// Number of free and pending pages.
+    pub(crate) fn count(&self) -> usize {
+        self.free_count() + self.pending_count()
+    }
+
+    pub(crate) fn free_count(&self) -> usize {
+        match &self.ids {
+            FreeIds::Array(ids) => ids.len(),
+            FreeIds::HashMap(index) => index.len(),
+        }
+    }
+
+    pub(crate) fn pending_count(&self) -> usize {
+        self.pending.values().map(Vec::len).sum()
+    }
+
+    /// Returns every free and pending page id, sorted.
+    pub(crate) fn all(&self) -> Vec<PageId> {
+        let mut pending: Vec<PageId> = self.pending.values().flatten().copied().collect();
+        pending.sort_unstable();
+        merge(&self.free_ids(), &pending)
+    }
+
+    /// Returns the released page ids, sorted.
+    pub(crate) fn free_ids(&self) -> Vec<PageId> {
+        match &self.ids {
+            FreeIds::Array(ids) => ids.clone(),
+            FreeIds::HashMap(index) => index.to_vec(),
+        }
+    }
+
+    /// Replaces the released page ids with `ids`, which must be sorted.
+    fn set_free_ids(&mut self, ids: Vec<PageId>) {
+        match &mut self.ids {
+            FreeIds::Array(old) => *old = ids,
+            FreeIds::HashMap(index) => *index = SpanIndex::from_ids(&ids),
+        }
+        self.reindex();
+    }
+
+    /// Takes `count` contiguous free pages and returns the id of the first
+    /// one, or `None` if there is no run that long.
+    pub(crate) fn allocate(&mut self, count: usize) -> Option<PageId> {
+        if count == 0 {
+            return None;
+        }
+        let page_id = match &mut self.ids {
+            FreeIds::Array(ids) => array_allocate(ids, count)?,
+            FreeIds::HashMap(index) => index.allocate(count)?,
+        };
+        for id in page_id..page_id + count as PageId {
+            self.cache.remove(&id);
+        }
+        Some(page_id)
+    }
+
+    /// Marks the page and its overflow pages as freed by `tx_id`. They become
+    /// reusable once released. Freeing a meta page or a page that is already
+    /// free means the tree is damaged, and would hand the page out twice.
+    pub(crate) fn free(
+        &mut self,
+        tx_id: TxId,
+        page_id: PageId,
+        overflow: u32,
+    ) -> Result<(), CorruptPage> {
+        if page_id < 2 {
+            return Err(CorruptPage {
+                page_id,
+                reason: "meta page freed",
+            });
+        }
+        let ids = page_id..=page_id + overflow as PageId;
+        if let Some(page_id) = ids.clone().find(|id| self.cache.contains(id)) {
+            return Err(CorruptPage {
+                page_id,
+                reason: "page freed twice",
+            });
+        }
+        self.cache.extend(ids.clone());
+        self.pending.entry(tx_id).or_default().extend(ids);
+        Ok(())
+    }
+
+    /// Moves the pages freed by transactions up to and including `tx_id` to
+    /// the free list.
+    pub(crate) fn release(&mut self, tx_id: TxId) {
+        let mut released = Vec::new();
+        while let Some(entry) = self.pending.first_entry() {
+            if *entry.key() > tx_id {
+                break;
+            }
+            released.extend(entry.remove());
+        }
+        if released.is_empty() {
+            return;
+        }
+        match &mut self.ids {
+            FreeIds::Array(ids) => {
+                released.sort_unstable();
+                *ids = merge(ids, &released);
+            }
+            FreeIds::HashMap(index) => {
+                for id in released {
+                    index.free(id);
+                }
+            }
+        }
+    }
+
+    /// Forgets the pages freed by `tx_id`, which is being rolled back.
+    pub(crate) fn rollback(&mut self, tx_id: TxId) {
+        if let Some(ids) = self.pending.remove(&tx_id) {
+            for id in ids {
+                self.cache.remove(&id);
+            }
+        }
+    }
+
+    /// Whether `page_id` is free or pending.
+    pub(crate) fn freed(&self, page_id: PageId) -> bool {
+        self.cache.contains(&page_id)
+    }
+
+    /// Replaces the free ids with the ones stored in `page`.
+    pub(crate) fn read(&mut self, page: &Page) -> Result<(), CorruptPage> {
+        self.read_ids(page.freelist_page_ids()?);
+        Ok(())
+    }
+
+    /// Replaces the free ids with `ids`, in any order.
+    pub(crate) fn read_ids(&mut self, mut ids: Vec<PageId>) {
+        ids.sort_unstable();
+        self.set_free_ids(ids);
+    }
+
+    /// Like `read`, but leaves out the ids that are still pending, since
+    /// pending pages are written to disk along with the free ones.
+    pub(crate) fn reload(&mut self, page: &Page) -> Result<(), CorruptPage> {
+        self.reload_ids(page.freelist_page_ids()?);
+        Ok(())
+    }
+
+    /// Like `read_ids`, but leaves out the ids that are still pending.
+    pub(crate) fn reload_ids(&mut self, mut ids: Vec<PageId>) {
+        let pending: HashSet<PageId> = self.pending.values().flatten().copied().collect();
+        ids.retain(|id| !pending.contains(id));
+        self.read_ids(ids);
+    }
+
+    /// Writes every free and pending id into `buf`, which must hold at least
+    /// `size()` bytes. Pending ids are included so that they are not lost if
+    /// the database is closed before they are released.
+    pub(crate) fn write(&self, buf: &mut [u8], page_id: PageId, overflow: u32) {
+        let count = self.count();
+        let (header_count, start) = if count >= u16::MAX as usize {
+            buf[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + 8]
+                .copy_from_slice(&(count as u64).to_le_bytes());
+            (u16::MAX, PAGE_HEADER_SIZE + 8)
+        } else {
+            (count as u16, PAGE_HEADER_SIZE)
+        };
+        Page::write_header(buf, page_id, FREELIST_PAGE_FLAG, header_count, overflow);
+
+        let mut pending: Vec<PageId> = self.pending.values().flatten().copied().collect();
+        pending.sort_unstable();
+        let mut ids = vec![0; count];
+        merge_page_ids(&mut ids, &self.free_ids(), &pending);
+        for (i, id) in ids.iter().enumerate() {
+            let offset = start + i * 8;
+            buf[offset..offset + 8].copy_from_slice(&id.to_le_bytes());
+        }
+    }
+
+    fn reindex(&mut self) {
+        self.cache = self.free_ids().into_iter().collect();
+        self.cache.extend(self.pending.values().flatten().copied());
+    }
+}
+
+/// Takes the first run of `count` contiguous ids out of the sorted `ids`.
+fn array_allocate(ids: &mut Vec<PageId>, count: usize) -> Option<PageId> {
+    let mut start = 0;
+    for i in 0..ids.len() {
+        if i > 0 && ids[i] != ids[i - 1] + 1 {
+            start = i;
+        }
+        if i + 1 - start == count {
+            let page_id = ids[start];
+            ids.drain(start..=i);
+            return Some(page_id);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::FreelistType;
+    use crate::freelist::Freelist;
+    use crate::page::{Page, PageId, FREELIST_PAGE_FLAG};
+
+    const TYPES: [FreelistType; 2] = [FreelistType::Array, FreelistType::HashMap];
+
+    fn with_ids(freelist_type: FreelistType, ids: &[PageId]) -> Freelist {
+        let mut f = Freelist::new(freelist_type);
+        f.set_free_ids(ids.to_vec());
+        f
+    }
+
+    #[test]
+    fn test_freelist_free_and_release() {
+        for freelist_type in TYPES {
+            let mut f = Freelist::new(freelist_type);
+            f.free(100, 12, 0).unwrap();
+            f.free(100, 9, 1).unwrap();
+            f.free(102, 39, 0).unwrap();
+            assert_eq!(f.pending_count(), 4);
+            assert!(f.freed(10));
+            assert_eq!(f.allocate(1), None);
+
+            f.release(100);
+            assert_eq!(f.free_ids(), [9, 10, 12]);
+            f.release(101);
+            assert_eq!(f.free_ids(), [9, 10, 12]);
+            f.release(102);
+            assert_eq!(f.free_ids(), [9, 10, 12, 39]);
+            assert_eq!(f.pending_count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_freelist_free_rejects_damaged_ids() {
+        for freelist_type in TYPES {
+            let mut f = Freelist::new(freelist_type);
+            assert_eq!(f.free(100, 1, 0).unwrap_err().page_id, 1);
+            f.free(100, 12, 0).unwrap();
+            assert_eq!(f.free(101, 10, 3).unwrap_err().page_id, 12);
+            assert_eq!(f.pending_count(), 1);
+            assert!(!f.freed(10));
+
+            f.release(101);
+            assert_eq!(f.free(102, 12, 0).unwrap_err().page_id, 12);
+            assert_eq!(f.free_ids(), [12]);
+        }
+    }
+
+    #[test]
+    fn test_freelist_allocate() {
+        let mut f = with_ids(FreelistType::Array, &[3, 4, 5, 6, 7, 9, 12, 13, 18]);
+        assert_eq!(f.allocate(3), Some(3));
+        assert_eq!(f.allocate(1), Some(6));
+        assert_eq!(f.allocate(3), None);
+        assert_eq!(f.allocate(2), Some(12));
+        assert_eq!(f.allocate(1), Some(7));
+        assert_eq!(f.allocate(0), None);
+        assert_eq!(f.free_ids(), [9, 18]);
+        assert!(!f.freed(12));
+        assert!(f.freed(18));
+        assert_eq!(f.allocate(1), Some(9));
+        assert_eq!(f.allocate(1), Some(18));
+        assert_eq!(f.allocate(1), None);
+    }
+
+    #[test]
+    fn test_freelist_rollback() {
+        for freelist_type in TYPES {
+            let mut f = Freelist::new(freelist_type);
+            f.free(100, 12, 0).unwrap();
+            f.free(101, 13, 0).unwrap();
+            f.rollback(101);
+            assert!(f.freed(12));
+            assert!(!f.freed(13));
+            assert_eq!(f.pending_count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_freelist_write_and_read() {
+        for freelist_type in TYPES {
+            let mut f = with_ids(freelist_type, &[12, 39]);
+            f.free(100, 28, 0).unwrap();
+            f.free(100, 11, 0).unwrap();
+            f.free(101, 3, 0).unwrap();
+            let mut buf = vec![0u8; 4096];
+            f.write(&mut buf, 2, 0);
+
+            let page = Page::from_bytes(&buf).unwrap();
+            assert_eq!(page.flag(), FREELIST_PAGE_FLAG as u16);
+            let mut g = Freelist::new(freelist_type);
+            g.read(&page).unwrap();
+            assert_eq!(g.free_ids(), [3, 11, 12, 28, 39]);
+
+            // Reloading leaves out the pages that are still pending.
+            f.reload(&page).unwrap();
+            assert_eq!(f.free_ids(), [12, 39]);
+        }
+    }
+
+    #[test]
+    fn test_freelist_count_overflow() {
+        let ids: Vec<PageId> = (2..0x1_0005).collect();
+        let f = with_ids(FreelistType::Array, &ids);
+        let mut buf = vec![0u8; f.size()];
+        f.write(&mut buf, 2, 0);
+
+        let page = Page::from_bytes(&buf).unwrap();
+        assert_eq!(page.count(), 0xFFFF);
+        let mut g = Freelist::new(FreelistType::HashMap);
+        g.read(&page).unwrap();
+        assert_eq!(g.free_ids(), ids);
+    }
+
+    /// Drives both freelists through the same pseudo-random history and checks
+    /// that they agree on every observable result. Allocations may pick
+    /// different runs, so they are made on copies: both have to find a run or
+    /// neither, and the run has to have been free.
+    #[test]
+    fn test_freelist_types_are_equivalent() {
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let mut rand = move |n: u64| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed % n
+        };
+
+        let mut array = Freelist::new(FreelistType::Array);
+        let mut hashmap = Freelist::new(FreelistType::HashMap);
+        let mut next_page = 2;
+        for tx_id in 1..500 {
+            for _ in 0..rand(8) {
+                let overflow = rand(3) as u32;
+                let page_id = next_page;
+                next_page += overflow as PageId + 1 + rand(2);
+                array.free(tx_id, page_id, overflow).unwrap();
+                hashmap.free(tx_id, page_id, overflow).unwrap();
+            }
+            match rand(10) {
+                0 => {
+                    array.rollback(tx_id);
+                    hashmap.rollback(tx_id);
+                }
+                1..=3 => {
+                    let release = tx_id - rand(5).min(tx_id);
+                    array.release(release);
+                    hashmap.release(release);
+                }
+                _ => {}
+            }
+
+            assert_eq!(array.all(), hashmap.all());
+            assert_eq!(array.free_ids(), hashmap.free_ids());
+            assert_eq!(array.pending_count(), hashmap.pending_count());
+            assert_eq!(array.size(), hashmap.size());
+
+            let free = array.free_ids();
+            for count in 1..6 {
+                let (mut a, mut h) = (array.clone(), hashmap.clone());
+                let (from_a, from_h) = (a.allocate(count), h.allocate(count));
+                assert_eq!(from_a.is_some(), from_h.is_some());
+                if let (Some(from_a), Some(from_h)) = (from_a, from_h) {
+                    for id in 0..count as PageId {
+                        assert!(free.binary_search(&(from_a + id)).is_ok());
+                        assert!(free.binary_search(&(from_h + id)).is_ok());
+                        assert!(!a.freed(from_a + id) && !h.freed(from_h + id));
+                    }
+                    assert_eq!(a.free_count(), h.free_count());
+                }
+            }
+
+            // Spend some of the free pages on both so they stay in step.
+            if rand(4) == 0 {
+                if let Some((_, ids)) = free.split_first() {
+                    array.set_free_ids(ids.to_vec());
+                    hashmap.set_free_ids(ids.to_vec());
+                }
+            }
+        }
+        assert!(array.free_count() > 100);
+    }
+}
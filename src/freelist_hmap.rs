@@ -0,0 +1,125 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::page::PageId;
+
+/// Free pages grouped into maximal runs of contiguous ids. Spans are indexed
+/// by size, so allocating a run is a lookup of the smallest span that fits,
+/// and by their first and last page, so freeing a page merges it with its
+/// neighbours in constant time.
+#[derive(Clone, Default)]
+pub(crate) struct SpanIndex {
+    /// First page of every span, by span size.
+    by_size: BTreeMap<u64, BTreeSet<PageId>>,
+    /// Span sizes by first page.
+    forward: HashMap<PageId, u64>,
+    /// Span sizes by last page.
+    backward: HashMap<PageId, u64>,
+    count: usize,
+}
+
+impl SpanIndex {
+    /// Builds the index from sorted page ids.
+    pub(crate) fn from_ids(ids: &[PageId]) -> SpanIndex {
+        let mut index = SpanIndex::default();
+        for run in ids.chunk_by(|a, b| b - a == 1) {
+            index.add_span(run[0], run.len() as u64);
+        }
+        index
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Returns every free id, sorted.
+    pub(crate) fn to_vec(&self) -> Vec<PageId> {
+        let mut spans: Vec<(PageId, u64)> = self
+            .forward
+            .iter()
+            .map(|(&start, &size)| (start, size))
+            .collect();
+        spans.sort_unstable();
+        spans
+            .into_iter()
+            .flat_map(|(start, size)| start..start + size)
+            .collect()
+    }
+
+    /// Takes `count` contiguous pages from the smallest span that holds them.
+    pub(crate) fn allocate(&mut self, count: usize) -> Option<PageId> {
+        let count = count as u64;
+        let (&size, starts) = self.by_size.range(count..).next()?;
+        let start = *starts.first().unwrap();
+        self.del_span(start, size);
+        if size > count {
+            self.add_span(start + count, size - count);
+        }
+        Some(start)
+    }
+
+    /// Adds a free page, merging it with the spans right before and after it.
+    pub(crate) fn free(&mut self, page_id: PageId) {
+        let (mut start, mut size) = (page_id, 1);
+        if let Some(&prev) = self.backward.get(&(page_id - 1)) {
+            start -= prev;
+            size += prev;
+            self.del_span(start, prev);
+        }
+        if let Some(&next) = self.forward.get(&(page_id + 1)) {
+            size += next;
+            self.del_span(page_id + 1, next);
+        }
+        self.add_span(start, size);
+    }
+
+    fn add_span(&mut self, start: PageId, size: u64) {
+        self.by_size.entry(size).or_default().insert(start);
+        self.forward.insert(start, size);
+        self.backward.insert(start + size - 1, size);
+        self.count += size as usize;
+    }
+
+    fn del_span(&mut self, start: PageId, size: u64) {
+        if let Some(starts) = self.by_size.get_mut(&size) {
+            starts.remove(&start);
+            if starts.is_empty() {
+                self.by_size.remove(&size);
+            }
+        }
+        self.forward.remove(&start);
+        self.backward.remove(&(start + size - 1));
+        self.count -= size as usize;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::freelist_hmap::SpanIndex;
+
+    #[test]
+    fn test_span_index_merges_spans() {
+        let mut index = SpanIndex::from_ids(&[3, 4, 5, 8, 9, 12]);
+        assert_eq!(index.forward.len(), 3);
+
+        index.free(7);
+        index.free(6);
+        assert_eq!(index.forward.get(&3), Some(&7));
+        assert_eq!(index.backward.get(&9), Some(&7));
+        assert_eq!(index.to_vec(), [3, 4, 5, 6, 7, 8, 9, 12]);
+
+        index.free(11);
+        assert_eq!(index.forward.get(&11), Some(&2));
+        assert_eq!(index.len(), 9);
+    }
+
+    #[test]
+    fn test_span_index_allocate_best_fit() {
+        let mut index = SpanIndex::from_ids(&[3, 4, 5, 6, 7, 9, 12, 13, 18]);
+        assert_eq!(index.allocate(2), Some(12));
+        assert_eq!(index.allocate(1), Some(9));
+        assert_eq!(index.allocate(3), Some(3));
+        assert_eq!(index.allocate(3), None);
+        assert_eq!(index.to_vec(), [6, 7, 18]);
+        assert_eq!(index.by_size.len(), 2);
+    }
+}
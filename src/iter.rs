@@ -0,0 +1,265 @@
+use std::iter::FusedIterator;
+use std::ops::Bound;
+
+use crate::cursor::{Cursor, Item};
+use crate::error::Result;
+
+/// Iterator over the items of a bucket within a key range, in either
+/// direction. Each end is driven by its own cursor; the iterator ends once the
+/// two meet.
+pub struct Iter<'tx> {
+    front: Cursor<'tx>,
+    back: Cursor<'tx>,
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+    front_key: Option<&'tx [u8]>,
+    back_key: Option<&'tx [u8]>,
+    done: bool,
+}
+
+impl<'tx> Iter<'tx> {
+    pub(crate) fn new(
+        front: Cursor<'tx>,
+        back: Cursor<'tx>,
+        lower: Bound<Vec<u8>>,
+        upper: Bound<Vec<u8>>,
+    ) -> Iter<'tx> {
+        Iter {
+            front,
+            back,
+            lower,
+            upper,
+            front_key: None,
+            back_key: None,
+            done: false,
+        }
+    }
+
+    fn front_item(&mut self) -> Result<Option<Item<'tx>>> {
+        if self.front_key.is_some() {
+            return self.front.next();
+        }
+        match &self.lower {
+            Bound::Included(key) => self.front.seek(key),
+            Bound::Excluded(key) => match self.front.seek(key)? {
+                Some((found, _)) if found == key.as_slice() => self.front.next(),
+                item => Ok(item),
+            },
+            Bound::Unbounded => self.front.first(),
+        }
+    }
+
+    fn back_item(&mut self) -> Result<Option<Item<'tx>>> {
+        if self.back_key.is_some() {
+            return self.back.prev();
+        }
+        match &self.upper {
+            Bound::Included(key) => match self.back.seek(key)? {
+                Some((found, value)) if found == key.as_slice() => Ok(Some((found, value))),
+                _ => self.back.prev(),
+            },
+            Bound::Excluded(key) => {
+                self.back.seek(key)?;
+                self.back.prev()
+            }
+            Bound::Unbounded => self.back.last(),
+        }
+    }
+
+    fn below_upper(&self, key: &[u8]) -> bool {
+        let below_back = self.back_key.is_none_or(|back| key < back);
+        below_back
+            && match &self.upper {
+                Bound::Included(upper) => key <= upper.as_slice(),
+                Bound::Excluded(upper) => key < upper.as_slice(),
+                Bound::Unbounded => true,
+            }
+    }
+
+    fn above_lower(&self, key: &[u8]) -> bool {
+        let above_front = self.front_key.is_none_or(|front| key > front);
+        above_front
+            && match &self.lower {
+                Bound::Included(lower) => key >= lower.as_slice(),
+                Bound::Excluded(lower) => key > lower.as_slice(),
+                Bound::Unbounded => true,
+            }
+    }
+}
+
+impl<'tx> Iterator for Iter<'tx> {
+    type Item = Result<Item<'tx>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.front_item() {
+            Ok(Some((key, value))) if self.below_upper(key) => {
+                self.front_key = Some(key);
+                Some(Ok((key, value)))
+            }
+            Ok(_) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl DoubleEndedIterator for Iter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.back_item() {
+            Ok(Some((key, value))) if self.above_lower(key) => {
+                self.back_key = Some(key);
+                Some(Ok((key, value)))
+            }
+            Ok(_) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl FusedIterator for Iter<'_> {}
+
+/// Returns the smallest key greater than every key starting with `prefix`, or
+/// `None` if there is none.
+pub(crate) fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use crate::db::DB;
+    use crate::error::Result;
+    use crate::iter::prefix_end;
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(prefix_end(b"abc"), Some(b"abd".to_vec()));
+        assert_eq!(prefix_end(b"a\xff\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_end(b"\xff"), None);
+        assert_eq!(prefix_end(b""), None);
+    }
+
+    #[test]
+    fn test_iter_range_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db")).unwrap();
+
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for key in ["a", "b1", "b2", "b3", "c", "d"] {
+                b.put(key.as_bytes(), key.as_bytes())?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?.unwrap();
+            let keys = |iter: crate::iter::Iter| -> Result<Vec<String>> {
+                iter.map(|item| item.map(|(key, _)| String::from_utf8(key.to_vec()).unwrap()))
+                    .collect()
+            };
+
+            assert_eq!(keys(b.iter())?, ["a", "b1", "b2", "b3", "c", "d"]);
+            assert_eq!(keys(b.prefix(b"b"))?, ["b1", "b2", "b3"]);
+            assert!(keys(b.prefix(b"x"))?.is_empty());
+            assert_eq!(
+                keys(b.range((Bound::Excluded(&b"b1"[..]), Bound::Included(&b"c"[..]))))?,
+                ["b2", "b3", "c"]
+            );
+            assert_eq!(
+                keys(b.range((Bound::Included(&b"b0"[..]), Bound::Excluded(&b"b3"[..]))))?,
+                ["b1", "b2"]
+            );
+
+            let rev: Vec<_> = b.prefix(b"b").rev().map(|item| item.unwrap().0).collect();
+            assert_eq!(rev, [&b"b3"[..], b"b2", b"b1"]);
+
+            // Both ends meet in the middle without yielding anything twice.
+            let mut iter = b.iter();
+            assert_eq!(iter.next().unwrap()?.0, b"a");
+            assert_eq!(iter.next_back().unwrap()?.0, b"d");
+            assert_eq!(iter.next_back().unwrap()?.0, b"c");
+            assert_eq!(iter.next().unwrap()?.0, b"b1");
+            assert_eq!(iter.by_ref().count(), 2);
+            assert!(iter.next_back().is_none());
+
+            let total: usize = b
+                .iter()
+                .filter_map(|item| item.ok())
+                .map(|(_, value)| value.unwrap().len())
+                .sum();
+            assert_eq!(total, 9);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_iter_rev_after_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db")).unwrap();
+        let key = |i: u32| format!("key-{i:04}").into_bytes();
+
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..1000 {
+                b.put(&key(i), &[0; 32])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        // Deletes empty whole leaves at the end and in the middle, which the
+        // backward scans have to step over within the same transaction.
+        db.update(|tx| {
+            let b = tx.bucket(b"widgets")?.unwrap();
+            for i in (300..400).chain(800..1000) {
+                b.delete(&key(i))?;
+            }
+            let expected: Vec<_> = (0..300).chain(400..800).rev().map(key).collect();
+            let rev = |iter: crate::iter::Iter| -> Result<Vec<Vec<u8>>> {
+                iter.rev()
+                    .map(|item| item.map(|(key, _)| key.to_vec()))
+                    .collect()
+            };
+
+            assert_eq!(rev(b.iter())?, expected);
+            let range = (
+                Bound::Included(&key(250)[..]),
+                Bound::Excluded(&key(900)[..]),
+            );
+            assert_eq!(rev(b.range(range))?, expected[..450]);
+            assert_eq!(rev(b.prefix(b"key-02"))?, expected[400..500]);
+            assert!(rev(b.prefix(b"key-03"))?.is_empty());
+            assert!(rev(b.prefix(b"key-09"))?.is_empty());
+            Ok(())
+        })
+        .unwrap();
+    }
+}
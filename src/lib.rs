@@ -1,6 +1,23 @@
 #![allow(dead_code)]
-#![feature(const_ptr_offset_from, const_refs_to_cell)]
 
+pub mod bench;
 pub mod bucket;
+mod check;
+mod compact;
+pub mod cursor;
+pub mod db;
+pub mod error;
+mod freelist;
+mod freelist_hmap;
+pub mod iter;
+mod node;
 pub mod page;
 pub mod transaction;
+
+pub use bucket::{Bucket, BucketStats, DEFAULT_FILL_PERCENT, MAX_KEY_SIZE, MAX_VALUE_SIZE};
+pub use compact::compact;
+pub use cursor::Cursor;
+pub use db::{FreelistType, Options, Stats, DB};
+pub use error::{Error, Result};
+pub use iter::Iter;
+pub use transaction::{Tx, TxStats};
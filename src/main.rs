@@ -0,0 +1,655 @@
+use std::error::Error as StdError;
+use std::fs::{self, File};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, process};
+
+use thrak::bench::{BenchOptions, KeyOrder, PhaseResults};
+use thrak::page::{Page, PageId, BUCKET_LEAF_FLAG, LEAF_PAGE_FLAG, NO_FREELIST};
+use thrak::{Bucket, BucketStats, Options, Tx, DB};
+
+type Result<T> = std::result::Result<T, Box<dyn StdError>>;
+
+const USAGE: &str = "\
+thrak is a tool for inspecting thrak databases.
+
+Usage:
+
+    thrak <command> [arguments]
+
+The commands are:
+
+    info     print the page size and the current meta
+    check    verify the integrity of the database
+    stats    print statistics of the buckets
+    pages    list every page with its type
+    page     print one or more pages, decoded and in hex
+    dump     print the buckets and keys of the database
+    compact  copy the database into a new, compacted file
+    bench    run a write and read benchmark on a new database
+    help     print this screen
+";
+
+const DEFAULT_TX_MAX_SIZE: usize = 65536;
+
+/// Values longer than this are cut short when printed.
+const MAX_VALUE_DISPLAY: usize = 64;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let Some((command, args)) = args.split_first() else {
+        eprint!("{USAGE}");
+        process::exit(2);
+    };
+    if let Err(err) = run(command, args) {
+        eprintln!("thrak: {err}");
+        process::exit(1);
+    }
+}
+
+fn run(command: &str, args: &[String]) -> Result<()> {
+    match command {
+        "info" => info(args),
+        "check" => check(args),
+        "stats" => stats(args),
+        "pages" => pages(args),
+        "page" => page(args),
+        "dump" => dump(args),
+        "compact" => compact(args),
+        "bench" => bench(args),
+        "help" | "-h" | "--help" => {
+            print!("{USAGE}");
+            Ok(())
+        }
+        _ => Err(format!("unknown command {command:?}, see \"thrak help\"").into()),
+    }
+}
+
+/// Opens the database read-only, so that the command can run next to the
+/// process that owns it.
+fn open(path: &str) -> Result<DB> {
+    if !Path::new(path).exists() {
+        return Err(format!("{path}: file not found").into());
+    }
+    let options = Options {
+        read_only: true,
+        ..Options::default()
+    };
+    Ok(DB::open_with_options(path, options)?)
+}
+
+/// Splits off the path every command takes first.
+fn path_arg<'a>(args: &'a [String], usage: &str) -> Result<(&'a str, &'a [String])> {
+    match args.split_first() {
+        Some((path, rest)) => Ok((path, rest)),
+        None => Err(format!("usage: thrak {usage}").into()),
+    }
+}
+
+fn info(args: &[String]) -> Result<()> {
+    let (path, _) = path_arg(args, "info PATH")?;
+    let db = open(path)?;
+    let meta = db.meta();
+    println!("Page Size: {}", db.page_size());
+    println!("Version: {}", meta.version);
+    println!("Tx: {}", meta.tx_id);
+    println!("Root: {}", meta.root);
+    if meta.freelist == NO_FREELIST {
+        println!("Freelist: not synced");
+    } else {
+        println!("Freelist: {}", meta.freelist);
+    }
+    println!("High Water Mark: {}", meta.page_id);
+    println!("Checksum: {:016x}", meta.checksum);
+    Ok(())
+}
+
+fn check(args: &[String]) -> Result<()> {
+    let (path, _) = path_arg(args, "check PATH")?;
+    let db = open(path)?;
+    let count = db.view(|tx| {
+        let mut count = 0;
+        for err in tx.check() {
+            println!("{err}");
+            count += 1;
+        }
+        Ok(count)
+    })?;
+    if count > 0 {
+        return Err(format!("{count} errors found").into());
+    }
+    println!("OK");
+    Ok(())
+}
+
+fn stats(args: &[String]) -> Result<()> {
+    let (path, rest) = path_arg(args, "stats PATH [PREFIX]")?;
+    let prefix = rest.first().map_or(&b""[..], |prefix| prefix.as_bytes());
+    let db = open(path)?;
+    let (count, s) = db.view(|tx| {
+        let mut count = 0;
+        let mut s = BucketStats::default();
+        for name in bucket_names(tx)? {
+            if name.starts_with(prefix) {
+                let bucket = tx.bucket(&name)?.expect("bucket listed by the cursor");
+                s.add(&bucket.stats()?);
+                count += 1;
+            }
+        }
+        Ok((count, s))
+    })?;
+
+    let percent = |part: usize, total: usize| (part * 100).checked_div(total).unwrap_or(0);
+    println!("Aggregate statistics for {count} buckets");
+    println!();
+    println!("Page count statistics");
+    println!("\tNumber of logical branch pages: {}", s.branch_pages);
+    println!(
+        "\tNumber of physical branch overflow pages: {}",
+        s.branch_overflow
+    );
+    println!("\tNumber of logical leaf pages: {}", s.leaf_pages);
+    println!(
+        "\tNumber of physical leaf overflow pages: {}",
+        s.leaf_overflow
+    );
+    println!("Tree statistics");
+    println!("\tNumber of keys/value pairs: {}", s.keys);
+    println!("\tNumber of levels in B+tree: {}", s.depth);
+    println!("Page size utilization");
+    println!(
+        "\tBytes allocated for physical branch pages: {}",
+        s.branch_alloc
+    );
+    println!(
+        "\tBytes actually used for branch data: {} ({}%)",
+        s.branch_in_use,
+        percent(s.branch_in_use, s.branch_alloc)
+    );
+    println!(
+        "\tBytes allocated for physical leaf pages: {}",
+        s.leaf_alloc
+    );
+    println!(
+        "\tBytes actually used for leaf data: {} ({}%)",
+        s.leaf_in_use,
+        percent(s.leaf_in_use, s.leaf_alloc)
+    );
+    println!("Bucket statistics");
+    println!("\tTotal number of buckets: {}", s.buckets);
+    println!(
+        "\tTotal number of inlined buckets: {} ({}%)",
+        s.inline_buckets,
+        percent(s.inline_buckets, s.buckets)
+    );
+    println!(
+        "\tBytes used for inlined buckets: {} ({}%)",
+        s.inline_bucket_in_use,
+        percent(s.inline_bucket_in_use, s.leaf_in_use)
+    );
+    Ok(())
+}
+
+fn pages(args: &[String]) -> Result<()> {
+    let (path, _) = path_arg(args, "pages PATH")?;
+    let db = open(path)?;
+    let infos = db.view(|tx| tx.page_infos())?;
+    println!("ID       TYPE       ITEMS  OVRFLW");
+    println!("======== ========== ====== ======");
+    for info in infos {
+        let overflow = if info.overflow > 0 {
+            info.overflow.to_string()
+        } else {
+            String::new()
+        };
+        println!(
+            "{:<8} {:<10} {:<6} {:<6}",
+            info.id, info.kind, info.count, overflow
+        );
+    }
+    Ok(())
+}
+
+fn page(args: &[String]) -> Result<()> {
+    let (path, ids) = path_arg(args, "page PATH ID...")?;
+    if ids.is_empty() {
+        return Err("usage: thrak page PATH ID...".into());
+    }
+    // The database is opened to hold the lock and learn the page size, but
+    // pages are read straight from the file so that any of them can be shown.
+    let db = open(path)?;
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    for (i, id) in ids.iter().enumerate() {
+        let page_id: PageId = id.parse().map_err(|_| format!("invalid page id {id:?}"))?;
+        let buf = read_page(&file, len, page_id, db.page_size())?;
+        if i > 0 {
+            println!();
+        }
+        print_page(&buf)?;
+    }
+    Ok(())
+}
+
+/// Reads the page stored at `page_id` along with its overflow pages.
+fn read_page(file: &File, len: u64, page_id: PageId, page_size: usize) -> Result<Vec<u8>> {
+    let beyond = || format!("page {page_id} is beyond the end of the file");
+    let offset = page_id.checked_mul(page_size as u64).ok_or_else(beyond)?;
+    let end = offset.checked_add(page_size as u64).ok_or_else(beyond)?;
+    if end > len {
+        return Err(beyond().into());
+    }
+    let mut buf = vec![0; page_size];
+    file.read_exact_at(&mut buf, offset)?;
+    let overflow = Page::from_bytes(&buf)?.overflow() as u64;
+    let size = ((overflow + 1) * page_size as u64).min(len - offset);
+    buf.resize(size as usize, 0);
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(buf)
+}
+
+fn print_page(buf: &[u8]) -> Result<()> {
+    let page = Page::from_bytes(buf)?;
+    println!("Page ID:    {}", page.id());
+    println!("Page Type:  {}", page.kind());
+    println!("Total Size: {} bytes", buf.len());
+    println!("Overflow:   {}", page.overflow());
+    println!("Item Count: {}", page.count());
+    println!();
+
+    match page.kind() {
+        "meta" => {
+            let meta = page.meta()?;
+            println!("Magic:      {:08x}", meta.magic);
+            println!("Version:    {}", meta.version);
+            println!("Page Size:  {}", meta.page_size);
+            println!("Flags:      {:08x}", meta.flags);
+            println!("Root:       {}", meta.root);
+            println!("Freelist:   {}", meta.freelist);
+            println!("HWM:        {}", meta.page_id);
+            println!("Tx:         {}", meta.tx_id);
+            println!("Checksum:   {:016x}", meta.checksum);
+        }
+        "freelist" => {
+            for id in page.freelist_page_ids()? {
+                println!("{id}");
+            }
+        }
+        "branch" => {
+            for elem in page.branch_page_elements() {
+                let elem = elem?;
+                println!("{}: <pgid={}>", format_bytes(elem.key()), elem.page_id());
+            }
+        }
+        "leaf" => {
+            for elem in page.leaf_page_elements() {
+                let elem = elem?;
+                let value = if elem.flag() & BUCKET_LEAF_FLAG as u32 != 0 {
+                    format_bucket_header(elem.value())
+                } else {
+                    format_value(elem.value())
+                };
+                println!("{}: {}", format_bytes(elem.key()), value);
+            }
+        }
+        _ => {}
+    }
+    println!();
+    print_hex(buf);
+    Ok(())
+}
+
+/// Describes a bucket stored as a leaf value: its root and sequence, and for
+/// inline buckets the number of items on the inline page.
+fn format_bucket_header(value: &[u8]) -> String {
+    let field = |i: usize| {
+        value
+            .get(i * 8..i * 8 + 8)
+            .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    let (Some(root), Some(sequence)) = (field(0), field(1)) else {
+        return "<bucket: header out of bounds>".to_string();
+    };
+    if root != 0 {
+        return format!("<bucket: root={root} sequence={sequence}>");
+    }
+    match Page::from_bytes(&value[16..]) {
+        Ok(page) if page.flag() & LEAF_PAGE_FLAG as u16 != 0 => format!(
+            "<bucket: inline items={} sequence={sequence}>",
+            page.count()
+        ),
+        _ => "<bucket: inline page corrupt>".to_string(),
+    }
+}
+
+fn print_hex(buf: &[u8]) {
+    for (i, line) in buf.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{b:02x}")).collect();
+        let ascii: String = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        println!("{:08x}  {:<47}  |{}|", i * 16, hex.join(" "), ascii);
+    }
+}
+
+fn dump(args: &[String]) -> Result<()> {
+    let (path, names) = path_arg(args, "dump PATH [BUCKET...]")?;
+    let db = open(path)?;
+    db.view(|tx| {
+        let Some((first, rest)) = names.split_first() else {
+            for name in bucket_names(tx)? {
+                println!("{}/", format_bytes(&name));
+                let bucket = tx.bucket(&name)?.expect("bucket listed by the cursor");
+                dump_bucket(&bucket, 1)?;
+            }
+            return Ok(());
+        };
+        let not_found = || thrak::Error::BucketNotFound;
+        let mut bucket = tx.bucket(first.as_bytes())?.ok_or_else(not_found)?;
+        for name in rest {
+            bucket = bucket.bucket(name.as_bytes())?.ok_or_else(not_found)?;
+        }
+        dump_bucket(&bucket, 0)
+    })?;
+    Ok(())
+}
+
+fn dump_bucket(bucket: &Bucket, depth: usize) -> thrak::Result<()> {
+    let indent = "  ".repeat(depth);
+    for item in bucket.iter() {
+        let (key, value) = item?;
+        match value {
+            Some(value) => println!("{indent}{} = {}", format_bytes(key), format_value(value)),
+            None => {
+                println!("{indent}{}/", format_bytes(key));
+                let child = bucket.bucket(key)?.expect("key holds a bucket");
+                dump_bucket(&child, depth + 1)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn compact(args: &[String]) -> Result<()> {
+    let usage = "usage: thrak compact -o DST [-tx-max-size BYTES] SRC";
+    let mut dst = None;
+    let mut tx_max_size = DEFAULT_TX_MAX_SIZE;
+    let mut src = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => dst = Some(args.next().ok_or(usage)?),
+            "-tx-max-size" => {
+                let size = args.next().ok_or(usage)?;
+                tx_max_size = size
+                    .parse()
+                    .map_err(|_| format!("invalid transaction size {size:?}"))?;
+            }
+            _ if src.is_none() => src = Some(arg),
+            _ => return Err(usage.into()),
+        }
+    }
+    let (Some(src), Some(dst)) = (src, dst) else {
+        return Err(usage.into());
+    };
+    if Path::new(dst).exists() {
+        return Err(format!("{dst}: file already exists").into());
+    }
+
+    let src_db = open(src)?;
+    let result = DB::open(dst).and_then(|dst_db| thrak::compact(&src_db, &dst_db, tx_max_size));
+    if let Err(err) = result {
+        // Leave no half-written copy behind.
+        let _ = fs::remove_file(dst);
+        return Err(err.into());
+    }
+
+    let src_size = fs::metadata(src)?.len();
+    let dst_size = fs::metadata(dst)?.len();
+    println!(
+        "{src_size} -> {dst_size} bytes (gain={:.2}x)",
+        src_size as f64 / dst_size as f64
+    );
+    Ok(())
+}
+
+fn bench(args: &[String]) -> Result<()> {
+    let usage = "usage: thrak bench [-count N] [-batch-size N] [-key-size N] [-value-size N] \
+                 [-write-mode seq|rnd|seq-nest|rnd-nest] [-read-mode seq|rnd] [-readers N] \
+                 [-seed N] [-no-sync] [-path PATH] [-work]";
+    let mut options = BenchOptions::default();
+    let mut path = None;
+    let mut work = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(usage);
+        let mut number = || -> Result<usize> {
+            let value = value()?;
+            Ok(value
+                .parse()
+                .map_err(|_| format!("invalid value {value:?} for {arg}"))?)
+        };
+        match arg.as_str() {
+            "-count" => options.count = number()?,
+            "-batch-size" => options.batch_size = number()?,
+            "-key-size" => options.key_size = number()?,
+            "-value-size" => options.value_size = number()?,
+            "-readers" => options.readers = number()?,
+            "-seed" => options.seed = number()? as u64,
+            "-write-mode" => {
+                (options.write_order, options.nested) = match value()?.as_str() {
+                    "seq" => (KeyOrder::Sequential, false),
+                    "rnd" => (KeyOrder::Random, false),
+                    "seq-nest" => (KeyOrder::Sequential, true),
+                    "rnd-nest" => (KeyOrder::Random, true),
+                    mode => return Err(format!("invalid write mode {mode:?}").into()),
+                }
+            }
+            "-read-mode" => {
+                options.read_order = match value()?.as_str() {
+                    "seq" => KeyOrder::Sequential,
+                    "rnd" => KeyOrder::Random,
+                    mode => return Err(format!("invalid read mode {mode:?}").into()),
+                }
+            }
+            "-no-sync" => options.no_sync = true,
+            "-path" => path = Some(PathBuf::from(value()?)),
+            "-work" => work = true,
+            _ => return Err(usage.into()),
+        }
+    }
+
+    let path =
+        path.unwrap_or_else(|| env::temp_dir().join(format!("thrak-bench-{}", process::id())));
+    if path.exists() {
+        return Err(format!("{}: file already exists", path.display()).into());
+    }
+    let results = thrak::bench::run(&path, &options);
+    if work {
+        println!("work: {}", path.display());
+    } else if path.exists() {
+        fs::remove_file(&path)?;
+    }
+    let results = results?;
+
+    let print_phase = |name: &str, phase: &PhaseResults| {
+        let per_op = match phase.ops {
+            0 => Duration::ZERO,
+            ops => phase.duration.div_f64(ops as f64),
+        };
+        let l = &phase.latency;
+        println!(
+            "# {name}\t{} ops in {:?}\t({:?}/op)\t({:.0} op/sec)\tp50={:?} p90={:?} p99={:?} max={:?}",
+            phase.ops,
+            phase.duration,
+            per_op,
+            phase.ops_per_sec(),
+            l.p50,
+            l.p90,
+            l.p99,
+            l.max
+        );
+    };
+    print_phase("Write", &results.write);
+    print_phase("Read", &results.read);
+    println!(
+        "# Bytes written: {}\t(file size: {})",
+        results.bytes_written, results.file_size
+    );
+    Ok(())
+}
+
+/// Returns the names of the top-level buckets.
+fn bucket_names(tx: &Tx) -> thrak::Result<Vec<Vec<u8>>> {
+    let mut names = Vec::new();
+    let mut cursor = tx.cursor();
+    let mut item = cursor.first()?;
+    while let Some((name, _)) = item {
+        names.push(name.to_vec());
+        item = cursor.next()?;
+    }
+    Ok(names)
+}
+
+fn format_value(value: &[u8]) -> String {
+    if value.len() <= MAX_VALUE_DISPLAY {
+        return format_bytes(value);
+    }
+    let prefix = format_bytes(&value[..MAX_VALUE_DISPLAY]);
+    format!("{prefix}... ({} bytes)", value.len())
+}
+
+/// Prints printable ASCII as is and anything else in hex.
+fn format_bytes(bytes: &[u8]) -> String {
+    if !bytes.is_empty() && bytes.iter().all(|&b| b.is_ascii_graphic() || b == b' ') {
+        return String::from_utf8_lossy(bytes).into_owned();
+    }
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    format!("0x{hex}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use thrak::DB;
+
+    use crate::{page, pages, read_page, run};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn err(result: crate::Result<()>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    /// Creates a small database with a bucket whose last value needs overflow
+    /// pages.
+    fn fixture(dir: &tempfile::TempDir) -> String {
+        let path = dir.path().join("db");
+        let db = DB::open(&path).unwrap();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..100u32 {
+                b.put(format!("key-{i:03}").as_bytes(), b"value")?;
+            }
+            b.put(b"large", &[0xab; 10000])
+        })
+        .unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_run_parses_arguments() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let path = path.to_str().unwrap();
+
+        assert!(run("help", &[]).is_ok());
+        assert_eq!(
+            err(run("nope", &[])),
+            "unknown command \"nope\", see \"thrak help\""
+        );
+        assert_eq!(err(run("info", &[])), "usage: thrak info PATH");
+        assert_eq!(
+            err(run("check", &args(&[path]))),
+            format!("{path}: file not found")
+        );
+        assert_eq!(
+            err(run("compact", &args(&["-o"]))),
+            "usage: thrak compact -o DST [-tx-max-size BYTES] SRC"
+        );
+        assert_eq!(
+            err(run(
+                "compact",
+                &args(&["-o", "dst", "-tx-max-size", "big", "src"])
+            )),
+            "invalid transaction size \"big\""
+        );
+        assert_eq!(
+            err(run("bench", &args(&["-count", "many"]))),
+            "invalid value \"many\" for -count"
+        );
+        assert_eq!(
+            err(run("bench", &args(&["-write-mode", "sideways"]))),
+            "invalid write mode \"sideways\""
+        );
+        assert!(err(run("bench", &args(&["-bogus"]))).starts_with("usage: thrak bench"));
+    }
+
+    #[test]
+    fn test_page_and_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir);
+
+        assert!(pages(&args(&[&path])).is_ok());
+        assert!(page(&args(&[&path, "0", "1", "2"])).is_ok());
+        assert_eq!(err(page(&args(&[&path]))), "usage: thrak page PATH ID...");
+        assert_eq!(err(page(&args(&[&path, "x"]))), "invalid page id \"x\"");
+        assert_eq!(
+            err(page(&args(&[&path, &u64::MAX.to_string()]))),
+            format!("page {} is beyond the end of the file", u64::MAX)
+        );
+    }
+
+    #[test]
+    fn test_read_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir);
+        let page_size = DB::open(&path).unwrap().page_size();
+        let file = File::open(&path).unwrap();
+        let len = file.metadata().unwrap().len();
+        let last = len / page_size as u64 - 1;
+
+        let buf = read_page(&file, len, 0, page_size).unwrap();
+        assert_eq!(buf.len(), page_size);
+        assert_eq!(thrak::page::Page::from_bytes(&buf).unwrap().kind(), "meta");
+        assert!(read_page(&file, len, last, page_size).is_ok());
+
+        // The overflow pages are read along with the page they belong to.
+        let large = (2..=last)
+            .map(|id| read_page(&file, len, id, page_size).unwrap())
+            .find(|buf| buf.len() > page_size)
+            .unwrap();
+        assert!(large.len() >= 10000);
+
+        // Neither the offset nor the end of the page may overflow.
+        for page_id in [last + 1, u64::MAX / page_size as u64, u64::MAX] {
+            assert_eq!(
+                read_page(&file, len, page_id, page_size)
+                    .unwrap_err()
+                    .to_string(),
+                format!("page {page_id} is beyond the end of the file")
+            );
+        }
+    }
+}
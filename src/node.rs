@@ -0,0 +1,597 @@
+use std::collections::HashMap;
+use std::mem;
+
+use crate::bucket::Bucket;
+use crate::error::{Error, Result};
+use crate::page::{
+    BranchPageElement, CorruptPage, LeafPageElement, Page, PageId, BRANCH_PAGE_ELEMENT_SIZE,
+    BRANCH_PAGE_FLAG, LEAF_PAGE_ELEMENT_SIZE, LEAF_PAGE_FLAG, MIN_KEYS_PER_PAGE, PAGE_HEADER_SIZE,
+};
+use crate::transaction::Slice;
+
+pub(crate) type NodeId = usize;
+
+/// In-memory copy of a page that is being modified by a writable transaction.
+/// Nodes live in the transaction's node arena and refer to each other by id.
+pub(crate) struct Node {
+    pub(crate) is_leaf: bool,
+    pub(crate) unbalanced: bool,
+    pub(crate) spilled: bool,
+    pub(crate) page_id: PageId,
+    /// First key of the node when it was read, which is the key its parent
+    /// still refers to it by.
+    pub(crate) key: Option<Slice>,
+    pub(crate) parent: Option<NodeId>,
+    /// Materialized children; untouched children stay in the mapped file.
+    pub(crate) children: Vec<NodeId>,
+    pub(crate) inodes: Vec<Inode>,
+}
+
+/// A single element of a node. Branch inodes only use `key` and `page_id`.
+#[derive(Clone, Copy)]
+pub(crate) struct Inode {
+    pub(crate) flags: u32,
+    pub(crate) page_id: PageId,
+    pub(crate) key: Slice,
+    pub(crate) value: Slice,
+}
+
+impl Node {
+    pub(crate) fn new(is_leaf: bool) -> Node {
+        Node {
+            is_leaf,
+            unbalanced: false,
+            spilled: false,
+            page_id: 0,
+            key: None,
+            parent: None,
+            children: Vec::new(),
+            inodes: Vec::new(),
+        }
+    }
+
+    /// Materializes `page` into a node. Keys and values keep pointing into the
+    /// page.
+    pub(crate) fn read(page: &Page) -> std::result::Result<Node, CorruptPage> {
+        let mut node = Node::new(page.is_leaf());
+        node.page_id = page.id();
+        if page.is_leaf() {
+            for elem in page.leaf_page_elements() {
+                let elem = elem?;
+                node.inodes.push(Inode {
+                    flags: elem.flag(),
+                    page_id: 0,
+                    key: Slice::new(elem.key()),
+                    value: Slice::new(elem.value()),
+                });
+            }
+        } else if page.is_branch() {
+            for elem in page.branch_page_elements() {
+                let elem = elem?;
+                node.inodes.push(Inode {
+                    flags: 0,
+                    page_id: elem.page_id(),
+                    key: Slice::new(elem.key()),
+                    value: Slice::default(),
+                });
+            }
+        } else {
+            return Err(page.corrupt("not a branch or leaf page"));
+        }
+        node.key = node.inodes.first().map(|inode| inode.key);
+        Ok(node)
+    }
+
+    /// Size of the node once written to a page.
+    pub(crate) fn size(&self) -> usize {
+        let elem_size = self.page_element_size();
+        self.inodes.iter().fold(PAGE_HEADER_SIZE, |size, inode| {
+            size + elem_size + inode.key.len() + inode.value.len()
+        })
+    }
+
+    /// Whether the node would fit in `size` bytes, without computing its full
+    /// size.
+    fn size_less_than(&self, size: usize) -> bool {
+        let elem_size = self.page_element_size();
+        let mut total = PAGE_HEADER_SIZE;
+        for inode in &self.inodes {
+            total += elem_size + inode.key.len() + inode.value.len();
+            if total >= size {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn page_element_size(&self) -> usize {
+        if self.is_leaf {
+            LEAF_PAGE_ELEMENT_SIZE
+        } else {
+            BRANCH_PAGE_ELEMENT_SIZE
+        }
+    }
+
+    /// Returns the index of the first inode whose key is not less than `key`,
+    /// and whether that key is an exact match.
+    pub(crate) fn search(&self, key: &[u8]) -> (usize, bool) {
+        match self
+            .inodes
+            .binary_search_by(|inode| inode.key.as_bytes().cmp(key))
+        {
+            Ok(idx) => (idx, true),
+            Err(idx) => (idx, false),
+        }
+    }
+
+    /// Inserts an inode, replacing the one stored under `old_key` if any.
+    pub(crate) fn put(
+        &mut self,
+        old_key: &[u8],
+        new_key: Slice,
+        value: Slice,
+        page_id: PageId,
+        flags: u32,
+    ) {
+        let inode = Inode {
+            flags,
+            page_id,
+            key: new_key,
+            value,
+        };
+        match self.search(old_key) {
+            (idx, true) => self.inodes[idx] = inode,
+            (idx, false) => self.inodes.insert(idx, inode),
+        }
+    }
+
+    pub(crate) fn del(&mut self, key: &[u8]) {
+        if let (idx, true) = self.search(key) {
+            self.inodes.remove(idx);
+            self.unbalanced = true;
+        }
+    }
+
+    fn min_keys(&self) -> usize {
+        if self.is_leaf {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// Returns the index at which to split the node so that the first part
+    /// fills up to `threshold` bytes, leaving at least `MIN_KEYS_PER_PAGE`
+    /// inodes on either side.
+    fn split_index(&self, threshold: usize) -> usize {
+        let elem_size = self.page_element_size();
+        let mut size = PAGE_HEADER_SIZE;
+        let mut index = 0;
+        for (i, inode) in self.inodes[..self.inodes.len() - MIN_KEYS_PER_PAGE]
+            .iter()
+            .enumerate()
+        {
+            index = i;
+            let inode_size = elem_size + inode.key.len() + inode.value.len();
+            if i >= MIN_KEYS_PER_PAGE && size + inode_size > threshold {
+                break;
+            }
+            size += inode_size;
+        }
+        index
+    }
+
+    /// Writes the node into `buf`, which must hold at least `size()` bytes.
+    pub(crate) fn write(&self, buf: &mut [u8], page_id: PageId, overflow: u32) {
+        let flag = if self.is_leaf {
+            LEAF_PAGE_FLAG
+        } else {
+            BRANCH_PAGE_FLAG
+        };
+        Page::write_header(buf, page_id, flag, self.inodes.len() as u16, overflow);
+
+        let elem_size = self.page_element_size();
+        let mut data = PAGE_HEADER_SIZE + self.inodes.len() * elem_size;
+        for (i, inode) in self.inodes.iter().enumerate() {
+            let offset = PAGE_HEADER_SIZE + i * elem_size;
+            let (key, value) = (inode.key.as_bytes(), inode.value.as_bytes());
+            if self.is_leaf {
+                LeafPageElement::write(
+                    &mut buf[offset..],
+                    inode.flags,
+                    data - offset,
+                    key.len(),
+                    value.len(),
+                );
+            } else {
+                BranchPageElement::write(
+                    &mut buf[offset..],
+                    data - offset,
+                    key.len(),
+                    inode.page_id,
+                );
+            }
+            buf[data..data + key.len()].copy_from_slice(key);
+            data += key.len();
+            buf[data..data + value.len()].copy_from_slice(value);
+            data += value.len();
+        }
+    }
+}
+
+/// Writes the node and its materialized descendants to freshly allocated
+/// pages, splitting any that outgrew a page, and points their parents at the
+/// new pages.
+pub(crate) fn spill(bucket: &Bucket, id: NodeId) -> Result<()> {
+    let tx = bucket.tx();
+    if tx.nodes.borrow()[id].spilled {
+        return Ok(());
+    }
+
+    // Splitting a child appends its new siblings to our children, so the
+    // length is checked on every iteration.
+    {
+        let mut nodes = tx.nodes.borrow_mut();
+        let mut children = mem::take(&mut nodes[id].children);
+        children.sort_by(|&a, &b| first_key(&nodes[a]).cmp(first_key(&nodes[b])));
+        nodes[id].children = children;
+    }
+    let mut i = 0;
+    loop {
+        let child = tx.nodes.borrow()[id].children.get(i).copied();
+        let Some(child) = child else {
+            break;
+        };
+        spill(bucket, child)?;
+        i += 1;
+    }
+    tx.nodes.borrow_mut()[id].children.clear();
+
+    let page_size = tx.page_size();
+    for node_id in split(bucket, id) {
+        let mut nodes = tx.nodes.borrow_mut();
+        let node = &mut nodes[node_id];
+        // The page the node was read from is replaced by a new one.
+        if node.page_id > 0 {
+            tx.free(node.page_id)?;
+            node.page_id = 0;
+        }
+        let count = node.size().div_ceil(page_size);
+        let overflow = u32::try_from(count - 1).map_err(|_| Error::ValueTooLarge)?;
+        let (page_id, mut buf) = tx.allocate(count);
+        node.write(&mut buf, page_id, overflow);
+        node.page_id = page_id;
+        node.spilled = true;
+        tx.write_page(page_id, buf);
+        tx.stats.borrow_mut().spills += 1;
+
+        if let Some(parent) = node.parent {
+            let key = node.inodes[0].key;
+            let old_key = node.key.unwrap_or(key);
+            node.key = Some(key);
+            nodes[parent].put(old_key.as_bytes(), key, Slice::default(), page_id, 0);
+        }
+    }
+
+    // A split root gets a new parent, which has to be written as well.
+    let parent = tx.nodes.borrow()[id].parent;
+    match parent {
+        Some(parent) if tx.nodes.borrow()[parent].page_id == 0 => spill(bucket, parent),
+        _ => Ok(()),
+    }
+}
+
+fn first_key(node: &Node) -> &[u8] {
+    node.inodes
+        .first()
+        .map_or(&[], |inode| inode.key.as_bytes())
+}
+
+/// Breaks the node up into page-sized nodes, returning it along with the new
+/// siblings that follow it.
+fn split(bucket: &Bucket, id: NodeId) -> Vec<NodeId> {
+    let mut ids = vec![id];
+    let mut current = id;
+    while let Some(next) = split_two(bucket, current) {
+        bucket.tx().stats.borrow_mut().splits += 1;
+        ids.push(next);
+        current = next;
+    }
+    ids
+}
+
+/// Moves the inodes past the fill threshold into a new sibling, creating a
+/// parent for the node if it is the root.
+fn split_two(bucket: &Bucket, id: NodeId) -> Option<NodeId> {
+    let tx = bucket.tx();
+    let page_size = tx.page_size();
+    let mut nodes = tx.nodes.borrow_mut();
+    let node = &nodes[id];
+    if node.inodes.len() <= MIN_KEYS_PER_PAGE * 2 || node.size_less_than(page_size) {
+        return None;
+    }
+    let threshold = (page_size as f64 * bucket.state().fill_percent) as usize;
+    let index = node.split_index(threshold);
+    let is_leaf = node.is_leaf;
+
+    let parent = match node.parent {
+        Some(parent) => parent,
+        None => {
+            let mut parent = Node::new(false);
+            parent.children.push(id);
+            nodes.push(parent);
+            let parent = nodes.len() - 1;
+            nodes[id].parent = Some(parent);
+            parent
+        }
+    };
+    let mut next = Node::new(is_leaf);
+    next.parent = Some(parent);
+    next.inodes = nodes[id].inodes.split_off(index);
+    nodes.push(next);
+    let next = nodes.len() - 1;
+    nodes[parent].children.push(next);
+    Some(next)
+}
+
+/// Merges the node into a sibling if deletes left it below a quarter page or
+/// with too few keys, then rebalances the parent.
+pub(crate) fn rebalance(bucket: &Bucket, id: NodeId) -> Result<()> {
+    let tx = bucket.tx();
+    {
+        let mut nodes = tx.nodes.borrow_mut();
+        let node = &mut nodes[id];
+        if !node.unbalanced {
+            return Ok(());
+        }
+        node.unbalanced = false;
+        tx.stats.borrow_mut().rebalances += 1;
+        if node.size() > tx.page_size() / 4 && node.inodes.len() > node.min_keys() {
+            return Ok(());
+        }
+    }
+
+    let (parent, is_leaf, count, key) = {
+        let nodes = tx.nodes.borrow();
+        let node = &nodes[id];
+        (node.parent, node.is_leaf, node.inodes.len(), node.key)
+    };
+    let Some(parent) = parent else {
+        // A root branch with a single child is replaced by that child.
+        if !is_leaf && count == 1 {
+            let child_page = tx.nodes.borrow()[id].inodes[0].page_id;
+            let child = bucket.node(child_page, Some(id))?;
+            let mut nodes = tx.nodes.borrow_mut();
+            let inodes = mem::take(&mut nodes[child].inodes);
+            let children = mem::take(&mut nodes[child].children);
+            nodes[id].is_leaf = nodes[child].is_leaf;
+            nodes[id].inodes = inodes;
+            nodes[id].children = children;
+            nodes[child].parent = None;
+            let mut state = bucket.state_mut();
+            for grandchild in nodes[id].children.clone() {
+                nodes[grandchild].parent = Some(id);
+            }
+            state.nodes.remove(&child_page);
+            free(bucket, &mut nodes[child])?;
+        }
+        return Ok(());
+    };
+
+    // An empty node is simply removed from its parent.
+    if count == 0 {
+        let mut nodes = tx.nodes.borrow_mut();
+        if let Some(key) = key {
+            nodes[parent].del(key.as_bytes());
+        }
+        nodes[parent].children.retain(|&child| child != id);
+        bucket.state_mut().nodes.remove(&nodes[id].page_id);
+        free(bucket, &mut nodes[id])?;
+        drop(nodes);
+        return rebalance(bucket, parent);
+    }
+
+    // Merge with the next sibling if this is the first child, and into the
+    // previous one otherwise.
+    let (index, sibling_page) = {
+        let nodes = tx.nodes.borrow();
+        let (index, _) = nodes[parent].search(key.unwrap_or_default().as_bytes());
+        let sibling = if index == 0 { index + 1 } else { index - 1 };
+        // Only the root may have a single child, so the file is damaged.
+        let Some(sibling) = nodes[parent].inodes.get(sibling) else {
+            return Err(Error::Corrupt {
+                page_id: nodes[parent].page_id,
+                reason: "branch page with a single child",
+            });
+        };
+        (index, sibling.page_id)
+    };
+    let target = bucket.node(sibling_page, Some(parent))?;
+    let (from, into) = if index == 0 {
+        (target, id)
+    } else {
+        (id, target)
+    };
+
+    let mut nodes = tx.nodes.borrow_mut();
+    let mut state = bucket.state_mut();
+    reparent(&mut nodes, &state.nodes, from, into);
+    let inodes = mem::take(&mut nodes[from].inodes);
+    nodes[into].inodes.extend(inodes);
+    if let Some(key) = nodes[from].key {
+        nodes[parent].del(key.as_bytes());
+    }
+    nodes[parent].children.retain(|&child| child != from);
+    state.nodes.remove(&nodes[from].page_id);
+    free(bucket, &mut nodes[from])?;
+    drop((nodes, state));
+    rebalance(bucket, parent)
+}
+
+/// Adds the page the node was read from to the freelist.
+pub(crate) fn free(bucket: &Bucket, node: &mut Node) -> Result<()> {
+    if node.page_id != 0 {
+        bucket.tx().free(node.page_id)?;
+        node.page_id = 0;
+    }
+    Ok(())
+}
+
+/// Makes `into` the parent of the materialized children of the branch inodes
+/// of `from`.
+fn reparent(nodes: &mut [Node], cache: &HashMap<PageId, NodeId>, from: NodeId, into: NodeId) {
+    if nodes[from].is_leaf {
+        return;
+    }
+    let children: Vec<NodeId> = nodes[from]
+        .inodes
+        .iter()
+        .filter_map(|inode| cache.get(&inode.page_id).copied())
+        .collect();
+    for child in children {
+        if let Some(old) = nodes[child].parent {
+            nodes[old].children.retain(|&c| c != child);
+        }
+        nodes[child].parent = Some(into);
+        nodes[into].children.push(child);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::FileExt;
+
+    use crate::db::DB;
+    use crate::error::Error;
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("key-{:08}", i * 7919 % 10_000).into_bytes()
+    }
+
+    #[test]
+    fn test_split_and_rebalance() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db")).unwrap();
+
+        for batch in 0..4u32 {
+            db.update(|tx| {
+                let b = tx.create_bucket_if_not_exists(b"widgets")?;
+                for i in batch * 2500..(batch + 1) * 2500 {
+                    b.put(&key(i), &i.to_le_bytes())?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?.unwrap();
+            // The data no longer fits in a single leaf.
+            assert!(!tx.page(b.header().root)?.is_leaf());
+            for i in 0..10_000 {
+                assert_eq!(b.get(&key(i))?, Some(&i.to_le_bytes()[..]));
+            }
+            let keys: Vec<&[u8]> = b.iter().map(|item| item.unwrap().0).collect();
+            assert_eq!(keys.len(), 10_000);
+            assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+            Ok(())
+        })
+        .unwrap();
+
+        db.update(|tx| {
+            let b = tx.bucket(b"widgets")?.unwrap();
+            for i in (0..10_000).filter(|i| i % 7 != 0) {
+                b.delete(&key(i))?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?.unwrap();
+            for i in 0..10_000u32 {
+                let expected = (i % 7 == 0).then(|| i.to_le_bytes());
+                assert_eq!(b.get(&key(i))?, expected.as_ref().map(|v| &v[..]));
+            }
+            assert_eq!(b.iter().count(), 1429);
+            Ok(())
+        })
+        .unwrap();
+
+        // Emptying the bucket collapses it back into a single leaf.
+        db.update(|tx| {
+            let b = tx.bucket(b"widgets")?.unwrap();
+            for i in (0..10_000).filter(|i| i % 7 == 0) {
+                b.delete(&key(i))?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?.unwrap();
+            assert_eq!(b.iter().count(), 0);
+            assert!(b.header().root == 0 || tx.page(b.header().root)?.is_leaf());
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_rebalance_single_child() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = DB::open(&path).unwrap();
+        let key = |i: u32| [&i.to_be_bytes()[..], &[0; 900]].concat();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..100 {
+                b.put(&key(i), b"x")?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        // Find the parent of the first leaf, which is not the root, and the
+        // keys of that leaf.
+        let (branch, keys) = db
+            .view(|tx| {
+                let root = tx.bucket(b"widgets")?.unwrap().header().root;
+                let mut branch = tx.page(root)?;
+                let mut leaf = tx.page(branch.branch_page_element(0)?.page_id())?;
+                while leaf.is_branch() {
+                    branch = leaf;
+                    leaf = tx.page(branch.branch_page_element(0)?.page_id())?;
+                }
+                assert_ne!(branch.id(), root);
+                let keys: Vec<Vec<u8>> = leaf
+                    .leaf_page_elements()
+                    .map(|elem| elem.unwrap().key().to_vec())
+                    .collect();
+                Ok((branch.id(), keys))
+            })
+            .unwrap();
+        drop(db);
+
+        // Leave the branch with only its first child.
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(&1u16.to_le_bytes(), branch * 4096 + 10)
+            .unwrap();
+        drop(file);
+
+        let db = DB::open(&path).unwrap();
+        let err = db
+            .update(|tx| {
+                let b = tx.bucket(b"widgets")?.unwrap();
+                for key in &keys[1..] {
+                    b.delete(key)?;
+                }
+                Ok(())
+            })
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Corrupt { page_id, .. } if page_id == branch
+        ));
+    }
+}
@@ -1,105 +1,382 @@
-use std::mem;
+use std::{fmt, mem};
 
 use crate::transaction::TxId;
 
 pub type PageId = u64;
 
-#[repr(C, packed)]
-pub struct Page {
+/// A page read from a buffer. The header is decoded up front; elements are
+/// decoded on access and checked against the bounds of the buffer, so a
+/// corrupt page surfaces as a `CorruptPage` error rather than a bad read.
+#[derive(Debug, Clone, Copy)]
+pub struct Page<'a> {
     page_id: PageId,
     flag: u16,
     count: u16,
-    overflow: u16,
-    body_ptr: u128,
+    overflow: u32,
+    buf: &'a [u8],
 }
 
-#[repr(C, packed)]
-pub struct BranchPageElement {
+#[derive(Debug, Clone, Copy)]
+pub struct BranchPageElement<'a> {
     pos: usize,
     key_size: usize,
     page_id: PageId,
+    buf: &'a [u8],
 }
 
-#[repr(C, packed)]
-pub struct LeafPageElement {
+#[derive(Debug, Clone, Copy)]
+pub struct LeafPageElement<'a> {
     flag: u32,
     pos: usize,
     key_size: usize,
     value_size: usize,
     page_id: PageId,
+    buf: &'a [u8],
 }
 
-#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
 pub struct Meta {
-    magic: u32,
-    version: u32,
-    page_size: u32,
-    flags: u32,
-    //root: &'a Bucket,
-    freelist: PageId,
-    page_id: PageId,
-    tx_id: TxId,
-    checksum: u64,
+    pub magic: u32,
+    pub version: u32,
+    pub page_size: u32,
+    pub flags: u32,
+    pub root: PageId,
+    pub freelist: PageId,
+    pub page_id: PageId,
+    pub tx_id: TxId,
+    pub checksum: u64,
+}
+
+/// Summary of a page, as listed by `Tx::page_infos`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageInfo {
+    pub id: PageId,
+    /// The type of the page, or "free" if it is on the freelist.
+    pub kind: &'static str,
+    pub count: usize,
+    pub overflow: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptPage {
+    pub page_id: PageId,
+    pub reason: &'static str,
+}
+
+pub(crate) const MAGIC: u32 = 0x7448_524b;
+pub(crate) const VERSION: u32 = 3;
+
+/// Value of `Meta::freelist` when the freelist is not written to disk.
+pub const NO_FREELIST: PageId = u64::MAX;
+
+// page_id: u64, flag: u16, count: u16, overflow: u32
+pub(crate) const PAGE_HEADER_SIZE: usize = 16;
+
+pub(crate) const META_SIZE: usize = mem::size_of::<Meta>();
+
+pub(crate) const MIN_KEYS_PER_PAGE: usize = 2;
+
+// pos: u64, key_size: u64, page_id: u64
+pub(crate) const BRANCH_PAGE_ELEMENT_SIZE: usize = 24;
+
+// flag: u32, pos: u64, key_size: u64, value_size: u64, page_id: u64
+pub(crate) const LEAF_PAGE_ELEMENT_SIZE: usize = 36;
+
+pub const BRANCH_PAGE_FLAG: u8 = 0x01; // 0000_0001
+pub const LEAF_PAGE_FLAG: u8 = 0x02; // 0000_0010
+pub const META_PAGE_FLAG: u8 = 0x04; // 0000_0100
+pub const FREELIST_PAGE_FLAG: u8 = 0x10; // 0001_0000
+
+pub const BUCKET_LEAF_FLAG: u8 = 0x01;
+
+fn read_u16(buf: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes(buf[pos..pos + 2].try_into().unwrap())
 }
 
-const PAGE_HEADER_SIZE: usize = memoffset::offset_of!(Page, body_ptr);
+fn read_u32(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(buf[pos..pos + 8].try_into().unwrap())
+}
+
+impl<'a> Page<'a> {
+    /// Decodes the header of the page stored at the start of `buf`.
+    pub fn from_bytes(buf: &'a [u8]) -> Result<Page<'a>, CorruptPage> {
+        if buf.len() < PAGE_HEADER_SIZE {
+            return Err(CorruptPage {
+                page_id: 0,
+                reason: "page shorter than its header",
+            });
+        }
+        Ok(Page {
+            page_id: read_u64(buf, 0),
+            flag: read_u16(buf, 8),
+            count: read_u16(buf, 10),
+            overflow: read_u32(buf, 12),
+            buf,
+        })
+    }
+
+    /// Encodes a page header into the first `PAGE_HEADER_SIZE` bytes of `buf`.
+    pub(crate) fn write_header(
+        buf: &mut [u8],
+        page_id: PageId,
+        flag: u8,
+        count: u16,
+        overflow: u32,
+    ) {
+        buf[0..8].copy_from_slice(&page_id.to_le_bytes());
+        buf[8..10].copy_from_slice(&(flag as u16).to_le_bytes());
+        buf[10..12].copy_from_slice(&count.to_le_bytes());
+        buf[12..16].copy_from_slice(&overflow.to_le_bytes());
+    }
+
+    pub fn id(&self) -> PageId {
+        self.page_id
+    }
+
+    pub fn flag(&self) -> u16 {
+        self.flag
+    }
+
+    pub fn count(&self) -> u16 {
+        self.count
+    }
+
+    pub fn overflow(&self) -> u32 {
+        self.overflow
+    }
+
+    pub fn is_branch(&self) -> bool {
+        self.flag & BRANCH_PAGE_FLAG as u16 != 0
+    }
 
-const MIN_KEYS_PER_PAGE: u8 = 2;
+    pub fn is_leaf(&self) -> bool {
+        self.flag & LEAF_PAGE_FLAG as u16 != 0
+    }
 
-const BRANCH_PAGE_ELEMENT_SIZE: usize = mem::size_of::<BranchPageElement>();
+    /// Returns the type of the page, as named by its flag.
+    pub fn kind(&self) -> &'static str {
+        if self.is_branch() {
+            "branch"
+        } else if self.is_leaf() {
+            "leaf"
+        } else if self.flag & META_PAGE_FLAG as u16 != 0 {
+            "meta"
+        } else if self.flag & FREELIST_PAGE_FLAG as u16 != 0 {
+            "freelist"
+        } else {
+            "unknown"
+        }
+    }
 
-const LEAF_PAGE_ELEMENT_SIZE: usize = mem::size_of::<LeafPageElement>();
+    pub(crate) fn corrupt(&self, reason: &'static str) -> CorruptPage {
+        CorruptPage {
+            page_id: self.page_id,
+            reason,
+        }
+    }
 
-const BRANCH_PAGE_FLAG: u8 = 0x01; // 0000_0001
-const LEAF_PAGE_FLAG: u8 = 0x02; // 0000_0010
-const META_PAGE_FLAG: u8 = 0x04; // 0000_0100
-const FREELIST_PAGE_FLAG: u8 = 0x10; // 0001_0000
+    pub fn meta(&self) -> Result<Meta, CorruptPage> {
+        if self.flag & META_PAGE_FLAG as u16 == 0 {
+            return Err(self.corrupt("not a meta page"));
+        }
+        if self.buf.len() < PAGE_HEADER_SIZE + META_SIZE {
+            return Err(self.corrupt("meta out of bounds"));
+        }
+        Ok(Meta::read(self.buf))
+    }
 
-const BUCKET_LEAF_FLAG: u8 = 0x01;
+    /// Returns the ids stored in a freelist page. A `count` of 0xFFFF means the
+    /// real count did not fit in the header and is stored as the first id.
+    pub fn freelist_page_ids(&self) -> Result<Vec<PageId>, CorruptPage> {
+        if self.flag & FREELIST_PAGE_FLAG as u16 == 0 {
+            return Err(self.corrupt("not a freelist page"));
+        }
+        let buf = &self.buf[PAGE_HEADER_SIZE..];
+        let out_of_bounds = self.corrupt("freelist out of bounds");
+        let (start, count): (usize, usize) = if self.count == u16::MAX {
+            if buf.len() < 8 {
+                return Err(out_of_bounds);
+            }
+            (1, read_u64(buf, 0) as usize)
+        } else {
+            (0, self.count as usize)
+        };
+        let end = start
+            .checked_add(count)
+            .filter(|end| end.checked_mul(8).is_some_and(|size| size <= buf.len()))
+            .ok_or(out_of_bounds)?;
+        Ok((start..end).map(|i| read_u64(buf, i * 8)).collect())
+    }
 
-impl Page {
-    unsafe fn meta(&self) -> &Meta {
-        mem::transmute::<u64, &Meta>(self.body_ptr as u64)
+    pub(crate) fn leaf_page_element(&self, idx: usize) -> Result<LeafPageElement<'a>, CorruptPage> {
+        if !self.is_leaf() {
+            return Err(self.corrupt("not a leaf page"));
+        }
+        let offset = self.element_offset(idx, LEAF_PAGE_ELEMENT_SIZE)?;
+        let buf = &self.buf[offset..];
+        let elem = LeafPageElement {
+            flag: read_u32(buf, 0),
+            pos: read_u64(buf, 4) as usize,
+            key_size: read_u64(buf, 12) as usize,
+            value_size: read_u64(buf, 20) as usize,
+            page_id: read_u64(buf, 28),
+            buf,
+        };
+        let end = elem
+            .pos
+            .checked_add(elem.key_size)
+            .and_then(|end| end.checked_add(elem.value_size));
+        match end {
+            Some(end) if elem.pos >= LEAF_PAGE_ELEMENT_SIZE && end <= buf.len() => Ok(elem),
+            _ => Err(self.corrupt("leaf element out of bounds")),
+        }
     }
 
-    unsafe fn leaf_page_element(&self, idx: usize) -> &LeafPageElement {
-        &mem::transmute::<u128, &[LeafPageElement]>(self.body_ptr)[idx]
+    pub fn leaf_page_elements(
+        &self,
+    ) -> impl Iterator<Item = Result<LeafPageElement<'a>, CorruptPage>> + '_ {
+        (0..self.count as usize).map(|idx| self.leaf_page_element(idx))
     }
 
-    unsafe fn leaf_page_elements(&self) -> Option<&[LeafPageElement]> {
-        if self.count == 0 {
-            return None;
+    pub(crate) fn branch_page_element(
+        &self,
+        idx: usize,
+    ) -> Result<BranchPageElement<'a>, CorruptPage> {
+        if !self.is_branch() {
+            return Err(self.corrupt("not a branch page"));
+        }
+        let offset = self.element_offset(idx, BRANCH_PAGE_ELEMENT_SIZE)?;
+        let buf = &self.buf[offset..];
+        let elem = BranchPageElement {
+            pos: read_u64(buf, 0) as usize,
+            key_size: read_u64(buf, 8) as usize,
+            page_id: read_u64(buf, 16),
+            buf,
+        };
+        match elem.pos.checked_add(elem.key_size) {
+            Some(end) if elem.pos >= BRANCH_PAGE_ELEMENT_SIZE && end <= buf.len() => Ok(elem),
+            _ => Err(self.corrupt("branch element out of bounds")),
         }
-        Some(mem::transmute::<u128, &[LeafPageElement]>(self.body_ptr))
     }
 
-    unsafe fn branch_page_element(&self, idx: usize) -> &BranchPageElement {
-        &mem::transmute::<u128, &[BranchPageElement]>(self.body_ptr)[idx]
+    pub fn branch_page_elements(
+        &self,
+    ) -> impl Iterator<Item = Result<BranchPageElement<'a>, CorruptPage>> + '_ {
+        (0..self.count as usize).map(|idx| self.branch_page_element(idx))
     }
 
-    unsafe fn branch_page_elements(&self) -> Option<&[BranchPageElement]> {
-        if self.count == 0 {
-            return None;
+    /// Returns the offset of the `idx`-th element, making sure both the index
+    /// and the whole element table fit in the page.
+    fn element_offset(&self, idx: usize, elem_size: usize) -> Result<usize, CorruptPage> {
+        if idx >= self.count as usize {
+            return Err(self.corrupt("element index out of range"));
+        }
+        if PAGE_HEADER_SIZE + self.count as usize * elem_size > self.buf.len() {
+            return Err(self.corrupt("element count exceeds page"));
         }
-        Some(mem::transmute::<u128, &[BranchPageElement]>(self.body_ptr))
+        Ok(PAGE_HEADER_SIZE + idx * elem_size)
+    }
+}
+
+impl<'a> BranchPageElement<'a> {
+    pub(crate) fn write(buf: &mut [u8], pos: usize, key_size: usize, page_id: PageId) {
+        buf[0..8].copy_from_slice(&(pos as u64).to_le_bytes());
+        buf[8..16].copy_from_slice(&(key_size as u64).to_le_bytes());
+        buf[16..24].copy_from_slice(&page_id.to_le_bytes());
+    }
+
+    pub fn key(&self) -> &'a [u8] {
+        &self.buf[self.pos..self.pos + self.key_size]
+    }
+
+    pub fn page_id(&self) -> PageId {
+        self.page_id
+    }
+}
+
+impl<'a> LeafPageElement<'a> {
+    pub(crate) fn write(buf: &mut [u8], flag: u32, pos: usize, key_size: usize, value_size: usize) {
+        buf[0..4].copy_from_slice(&flag.to_le_bytes());
+        buf[4..12].copy_from_slice(&(pos as u64).to_le_bytes());
+        buf[12..20].copy_from_slice(&(key_size as u64).to_le_bytes());
+        buf[20..28].copy_from_slice(&(value_size as u64).to_le_bytes());
+        buf[28..36].copy_from_slice(&0u64.to_le_bytes());
+    }
+
+    pub fn flag(&self) -> u32 {
+        self.flag
+    }
+
+    pub fn key(&self) -> &'a [u8] {
+        &self.buf[self.pos..self.pos + self.key_size]
+    }
+
+    pub fn value(&self) -> &'a [u8] {
+        let start = self.pos + self.key_size;
+        &self.buf[start..start + self.value_size]
     }
 }
 
-impl LeafPageElement {
-    unsafe fn key(&self) -> &[u8] {
-        let ptr = self as *const LeafPageElement as u128;
-        let buf = mem::transmute::<u128, &[u8]>(ptr);
-        &buf[self.pos..(self.pos + self.key_size)]
+impl Meta {
+    /// Decodes a meta stored right after the page header of a meta page.
+    pub(crate) fn read(buf: &[u8]) -> Meta {
+        let buf = &buf[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + META_SIZE];
+        Meta {
+            magic: read_u32(buf, 0),
+            version: read_u32(buf, 4),
+            page_size: read_u32(buf, 8),
+            flags: read_u32(buf, 12),
+            root: read_u64(buf, 16),
+            freelist: read_u64(buf, 24),
+            page_id: read_u64(buf, 32),
+            tx_id: read_u64(buf, 40),
+            checksum: read_u64(buf, 48),
+        }
+    }
+    /// Encodes the meta right after the page header of a meta page, updating
+    /// its checksum first.
+    pub(crate) fn write(&mut self, buf: &mut [u8]) {
+        self.checksum = self.sum64();
+        self.encode(&mut buf[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + META_SIZE]);
+    }
+
+    /// FNV-1a checksum over every field preceding `checksum`.
+    pub(crate) fn sum64(&self) -> u64 {
+        let mut buf = [0u8; META_SIZE];
+        self.encode(&mut buf);
+        buf[..META_SIZE - 8]
+            .iter()
+            .fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+                (hash ^ *b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+            })
     }
 
-    unsafe fn value(&self) -> &[u8] {
-        let ptr = self as *const LeafPageElement as u128;
-        let buf = mem::transmute::<u128, &[u8]>(ptr);
-        &buf[self.pos..(self.pos + self.value_size)]
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0..4].copy_from_slice(&self.magic.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.version.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.page_size.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.flags.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.root.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.freelist.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.page_id.to_le_bytes());
+        buf[40..48].copy_from_slice(&self.tx_id.to_le_bytes());
+        buf[48..56].copy_from_slice(&self.checksum.to_le_bytes());
     }
 }
 
-fn merge(a: &Vec<PageId>, b: &Vec<PageId>) -> Vec<PageId> {
+impl fmt::Display for CorruptPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "page {} is corrupt: {}", self.page_id, self.reason)
+    }
+}
+
+impl std::error::Error for CorruptPage {}
+
+pub(crate) fn merge(a: &[PageId], b: &[PageId]) -> Vec<PageId> {
     if a.is_empty() {
         return b.to_owned();
     }
@@ -114,13 +391,13 @@ fn merge(a: &Vec<PageId>, b: &Vec<PageId>) -> Vec<PageId> {
     merged
 }
 
-fn merge_page_ids(dst: &mut [PageId], a: &Vec<PageId>, b: &Vec<PageId>) {
+pub(crate) fn merge_page_ids(dst: &mut [PageId], a: &[PageId], b: &[PageId]) {
     if a.is_empty() {
-        dst[..b.len()].copy_from_slice(&b[..]);
+        dst[..b.len()].copy_from_slice(b);
         return;
     }
     if b.is_empty() {
-        dst[..a.len()].copy_from_slice(&a[..]);
+        dst[..a.len()].copy_from_slice(a);
         return;
     }
 
@@ -146,7 +423,92 @@ fn merge_page_ids(dst: &mut [PageId], a: &Vec<PageId>, b: &Vec<PageId>) {
 
 #[cfg(test)]
 mod tests {
-    use crate::page::{merge, PageId};
+    use crate::page::{
+        merge, BranchPageElement, LeafPageElement, Page, PageId, BRANCH_PAGE_ELEMENT_SIZE,
+        BRANCH_PAGE_FLAG, LEAF_PAGE_ELEMENT_SIZE, LEAF_PAGE_FLAG, PAGE_HEADER_SIZE,
+    };
+
+    fn leaf_page(items: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut buf = vec![0u8; 256];
+        Page::write_header(&mut buf, 7, LEAF_PAGE_FLAG, items.len() as u16, 0);
+        let mut data = PAGE_HEADER_SIZE + items.len() * LEAF_PAGE_ELEMENT_SIZE;
+        for (i, (key, value)) in items.iter().enumerate() {
+            let offset = PAGE_HEADER_SIZE + i * LEAF_PAGE_ELEMENT_SIZE;
+            LeafPageElement::write(&mut buf[offset..], 0, data - offset, key.len(), value.len());
+            buf[data..data + key.len()].copy_from_slice(key);
+            buf[data + key.len()..data + key.len() + value.len()].copy_from_slice(value);
+            data += key.len() + value.len();
+        }
+        buf
+    }
+
+    #[test]
+    fn test_decode_leaf_page() {
+        let buf = leaf_page(&[(b"foo", b"bar"), (b"hello", b"world!")]);
+        let page = Page::from_bytes(&buf).unwrap();
+        assert_eq!(page.id(), 7);
+        assert!(page.is_leaf());
+
+        let elems = page
+            .leaf_page_elements()
+            .map(|elem| elem.map(|elem| (elem.key(), elem.value())))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            elems,
+            vec![(&b"foo"[..], &b"bar"[..]), (&b"hello"[..], &b"world!"[..])]
+        );
+        assert!(page.leaf_page_element(2).is_err());
+        assert!(page.branch_page_element(0).is_err());
+
+        let mut buf = vec![0u8; 128];
+        Page::write_header(&mut buf, 3, BRANCH_PAGE_FLAG, 1, 0);
+        BranchPageElement::write(
+            &mut buf[PAGE_HEADER_SIZE..],
+            BRANCH_PAGE_ELEMENT_SIZE,
+            3,
+            42,
+        );
+        buf[PAGE_HEADER_SIZE + BRANCH_PAGE_ELEMENT_SIZE..][..3].copy_from_slice(b"key");
+        let elem = Page::from_bytes(&buf)
+            .unwrap()
+            .branch_page_element(0)
+            .unwrap();
+        assert_eq!((elem.key(), elem.page_id()), (&b"key"[..], 42));
+    }
+
+    #[test]
+    fn test_decode_rejects_corrupt_page() {
+        assert!(Page::from_bytes(&[0u8; 4]).is_err());
+
+        // Element table larger than the page.
+        let mut buf = leaf_page(&[(b"foo", b"bar")]);
+        buf[10..12].copy_from_slice(&100u16.to_le_bytes());
+        let page = Page::from_bytes(&buf).unwrap();
+        assert_eq!(
+            page.leaf_page_element(0).unwrap_err().reason,
+            "element count exceeds page"
+        );
+
+        // Value running past the end of the page.
+        let mut buf = leaf_page(&[(b"foo", b"bar")]);
+        let value_size = PAGE_HEADER_SIZE + 20;
+        buf[value_size..value_size + 8].copy_from_slice(&1000u64.to_le_bytes());
+        let err = Page::from_bytes(&buf)
+            .unwrap()
+            .leaf_page_element(0)
+            .unwrap_err();
+        assert_eq!(err.page_id, 7);
+
+        // Overflowing position.
+        let mut buf = leaf_page(&[(b"foo", b"bar")]);
+        let pos = PAGE_HEADER_SIZE + 4;
+        buf[pos..pos + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(Page::from_bytes(&buf)
+            .unwrap()
+            .leaf_page_element(0)
+            .is_err());
+    }
 
     #[test]
     fn test_merge_page_ids() {
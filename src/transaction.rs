@@ -1 +1,499 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::slice;
+use std::sync::MutexGuard;
+use std::time::{Duration, Instant};
+
+use crate::bucket::{Bucket, BucketHeader, BucketState};
+use crate::check;
+use crate::cursor::Cursor;
+use crate::db::{self, PageSource, DB};
+use crate::error::{Error, Result};
+use crate::node::{Node, NodeId};
+use crate::page::{Meta, Page, PageId, PageInfo, META_PAGE_FLAG, NO_FREELIST};
+
 pub type TxId = u64;
+
+pub struct Tx<'db> {
+    db: &'db DB,
+    meta: Cell<Meta>,
+    pages: Option<PageSource<'db>>,
+    writer: Option<MutexGuard<'db, ()>>,
+    pub(crate) buckets: RefCell<Vec<BucketState>>,
+    pub(crate) nodes: RefCell<Vec<Node>>,
+    arena: RefCell<Vec<Box<[u8]>>>,
+    dirty: RefCell<BTreeMap<PageId, Vec<u8>>>,
+    pub(crate) stats: RefCell<TxStats>,
+}
+
+/// Counters of the work done by transactions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TxStats {
+    /// Pages allocated, and their size in bytes.
+    pub pages: u64,
+    pub page_alloc: u64,
+    /// Nodes materialized from pages.
+    pub nodes: u64,
+    /// Nodes checked for merging on commit, and the time spent on it.
+    pub rebalances: u64,
+    pub rebalance_time: Duration,
+    /// Nodes split because they outgrew a page.
+    pub splits: u64,
+    /// Nodes written to new pages, and the time spent on it.
+    pub spills: u64,
+    pub spill_time: Duration,
+    /// Writes to the file, the bytes written, and the time spent on them and
+    /// on syncing.
+    pub writes: u64,
+    pub write_bytes: u64,
+    pub write_time: Duration,
+}
+
+impl TxStats {
+    pub fn add(&mut self, other: &TxStats) {
+        self.pages += other.pages;
+        self.page_alloc += other.page_alloc;
+        self.nodes += other.nodes;
+        self.rebalances += other.rebalances;
+        self.rebalance_time += other.rebalance_time;
+        self.splits += other.splits;
+        self.spills += other.spills;
+        self.spill_time += other.spill_time;
+        self.writes += other.writes;
+        self.write_bytes += other.write_bytes;
+        self.write_time += other.write_time;
+    }
+
+    /// Returns the counters accumulated since the `other` snapshot was taken.
+    /// Counters that went down, because the snapshots were passed the wrong
+    /// way round, come out as zero.
+    pub fn sub(&self, other: &TxStats) -> TxStats {
+        TxStats {
+            pages: self.pages.saturating_sub(other.pages),
+            page_alloc: self.page_alloc.saturating_sub(other.page_alloc),
+            nodes: self.nodes.saturating_sub(other.nodes),
+            rebalances: self.rebalances.saturating_sub(other.rebalances),
+            rebalance_time: self.rebalance_time.saturating_sub(other.rebalance_time),
+            splits: self.splits.saturating_sub(other.splits),
+            spills: self.spills.saturating_sub(other.spills),
+            spill_time: self.spill_time.saturating_sub(other.spill_time),
+            writes: self.writes.saturating_sub(other.writes),
+            write_bytes: self.write_bytes.saturating_sub(other.write_bytes),
+            write_time: self.write_time.saturating_sub(other.write_time),
+        }
+    }
+}
+
+/// Bytes owned by a transaction: either a region of the mapped file or a
+/// buffer in the transaction's arena. Both stay in place until the
+/// transaction is closed, which is what makes handing them out as `&'tx [u8]`
+/// sound.
+#[derive(Clone, Copy)]
+pub(crate) struct Slice {
+    ptr: *const u8,
+    len: usize,
+}
+
+impl Default for Slice {
+    fn default() -> Self {
+        Slice::new(&[])
+    }
+}
+
+impl Slice {
+    pub(crate) fn new(bytes: &[u8]) -> Slice {
+        Slice {
+            ptr: bytes.as_ptr(),
+            len: bytes.len(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl<'db> Tx<'db> {
+    pub(crate) fn new(
+        db: &'db DB,
+        mut meta: Meta,
+        pages: PageSource<'db>,
+        writer: Option<MutexGuard<'db, ()>>,
+    ) -> Tx<'db> {
+        if writer.is_some() {
+            meta.tx_id += 1;
+        }
+        let root = BucketState::new(BucketHeader {
+            root: meta.root,
+            sequence: 0,
+        });
+        Tx {
+            db,
+            meta: Cell::new(meta),
+            pages: Some(pages),
+            writer,
+            buckets: RefCell::new(vec![root]),
+            nodes: RefCell::new(Vec::new()),
+            arena: RefCell::new(Vec::new()),
+            dirty: RefCell::new(BTreeMap::new()),
+            stats: RefCell::new(TxStats::default()),
+        }
+    }
+
+    pub fn id(&self) -> TxId {
+        self.meta.get().tx_id
+    }
+
+    pub fn writable(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    pub fn db(&self) -> &'db DB {
+        self.db
+    }
+
+    /// Returns the work done by the transaction so far.
+    pub fn stats(&self) -> TxStats {
+        *self.stats.borrow()
+    }
+
+    /// Returns a cursor over the names of the top-level buckets.
+    pub fn cursor(&self) -> Cursor<'_> {
+        self.root().cursor()
+    }
+
+    /// Returns the top-level bucket called `name`, if it exists.
+    pub fn bucket(&self, name: &[u8]) -> Result<Option<Bucket<'_>>> {
+        self.root().bucket(name)
+    }
+
+    pub fn create_bucket(&self, name: &[u8]) -> Result<Bucket<'_>> {
+        self.root().create_bucket(name)
+    }
+
+    pub fn create_bucket_if_not_exists(&self, name: &[u8]) -> Result<Bucket<'_>> {
+        self.root().create_bucket_if_not_exists(name)
+    }
+
+    pub fn delete_bucket(&self, name: &[u8]) -> Result<()> {
+        self.root().delete_bucket(name)
+    }
+
+    /// Checks the consistency of the database and returns every problem found,
+    /// rather than stopping at the first one. Pages are checked as last
+    /// committed; changes made by this transaction are not looked at.
+    pub fn check(&self) -> impl Iterator<Item = Error> {
+        check::check(self, &self.committed_meta()).into_iter()
+    }
+
+    /// Lists every page below the high-water mark, in order. Overflow pages
+    /// are part of the page before them, while free pages are listed one by
+    /// one since what their headers say may be stale. Pages that cannot be
+    /// decoded are listed as corrupt, so that damaged files can be looked at.
+    pub fn page_infos(&self) -> Result<Vec<PageInfo>> {
+        let pages = self.pages.as_ref().ok_or(Error::TxClosed)?;
+        let meta = self.committed_meta();
+        let free: HashSet<PageId> = if meta.freelist == NO_FREELIST {
+            db::free_pages(pages, &meta).unwrap_or_default()
+        } else {
+            self.page(meta.freelist)
+                .and_then(|page| Ok(page.freelist_page_ids()?))
+                .unwrap_or_default()
+        }
+        .into_iter()
+        .collect();
+
+        let mut infos = Vec::new();
+        let mut page_id = 0;
+        while page_id < meta.page_id.min(self.mapped_pages()) {
+            let info = if free.contains(&page_id) {
+                PageInfo {
+                    id: page_id,
+                    kind: "free",
+                    count: 0,
+                    overflow: 0,
+                }
+            } else {
+                match self.page(page_id) {
+                    Ok(page) => PageInfo {
+                        id: page_id,
+                        kind: page.kind(),
+                        count: page.count() as usize,
+                        overflow: page.overflow(),
+                    },
+                    Err(_) => PageInfo {
+                        id: page_id,
+                        kind: "corrupt",
+                        count: 0,
+                        overflow: 0,
+                    },
+                }
+            };
+            page_id += info.overflow as PageId + 1;
+            infos.push(info);
+        }
+        Ok(infos)
+    }
+
+    /// Writes a copy of the database as seen by the transaction to `w`, and
+    /// returns the number of bytes written. Writers carry on in the meantime,
+    /// since the pages the transaction sees are not reused while it is open.
+    /// Both meta pages of the copy point at the transaction's snapshot.
+    pub fn write_to<W: Write>(&self, mut w: W) -> Result<u64> {
+        let pages = self.pages.as_ref().ok_or(Error::TxClosed)?;
+        let page_size = self.page_size();
+        let meta = self.committed_meta();
+
+        let mut buf = vec![0u8; page_size];
+        for page_id in 0..2 {
+            let mut meta = meta;
+            if page_id != meta.tx_id % 2 {
+                meta.tx_id = meta.tx_id.saturating_sub(1);
+            }
+            buf.fill(0);
+            Page::write_header(&mut buf, page_id, META_PAGE_FLAG, 0, 0);
+            meta.write(&mut buf);
+            w.write_all(&buf)?;
+        }
+
+        let len = (meta.page_id - 2) as usize * page_size;
+        let data = pages.bytes(2 * page_size, len).ok_or(Error::Corrupt {
+            page_id: meta.page_id,
+            reason: "high-water mark beyond the end of the file",
+        })?;
+        w.write_all(data)?;
+        Ok(meta.page_id * page_size as u64)
+    }
+
+    /// Writes a copy of the database as seen by the transaction to the file
+    /// at `path`, replacing it if it exists.
+    pub fn copy_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut file = File::create(path)?;
+        self.write_to(&mut file)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Writes the transaction's changes to disk and makes them visible to new
+    /// transactions. The transaction is rolled back if this fails.
+    pub fn commit(mut self) -> Result<()> {
+        if !self.writable() {
+            return Err(Error::TxNotWritable);
+        }
+        let start = Instant::now();
+        self.root().rebalance()?;
+        self.stats.get_mut().rebalance_time += start.elapsed();
+
+        let start = Instant::now();
+        self.root().spill()?;
+        self.stats.get_mut().spill_time += start.elapsed();
+
+        // The freelist is rewritten to a new page on every commit, and the
+        // page holding the previous version is freed.
+        let old_freelist = self.meta.get().freelist;
+        if old_freelist != NO_FREELIST {
+            self.free(old_freelist)?;
+        }
+        let freelist = if self.db.no_freelist_sync() {
+            NO_FREELIST
+        } else {
+            let count = self.db.freelist().size() / self.page_size() + 1;
+            let (freelist, mut buf) = self.allocate(count);
+            self.db
+                .freelist()
+                .write(&mut buf, freelist, (count - 1) as u32);
+            self.write_page(freelist, buf);
+            freelist
+        };
+
+        let mut meta = self.meta.get();
+        meta.root = self.buckets.get_mut()[0].header.root;
+        meta.freelist = freelist;
+
+        // Every page is encoded by now, so the nodes, which may point into the
+        // mapping, can go before the mapping is grown to cover the new pages.
+        // Growing it can fail, which has to happen before anything is written.
+        // The pages are taken again either way, as rolling back on failure
+        // needs them to restore the freelist.
+        self.nodes.get_mut().clear();
+        self.pages = None;
+        let remapped = self
+            .db
+            .remap((meta.page_id * self.db.page_size() as u64) as usize);
+        self.pages = Some(self.db.pages());
+        remapped?;
+
+        // The pages have to be on disk before the meta pointing at them, or a
+        // crash in between could leave a valid meta referring to garbage.
+        let start = Instant::now();
+        self.write_pages()?;
+        self.db.write_meta(&mut meta)?;
+        let stats = self.stats.get_mut();
+        stats.writes += 1;
+        stats.write_bytes += self.db.page_size() as u64;
+        stats.write_time += start.elapsed();
+
+        self.pages = None;
+        self.db.set_meta(meta);
+        Ok(())
+    }
+
+    /// Writes the dirty pages in page order, merging runs of adjacent pages
+    /// into a single write, and syncs the file.
+    fn write_pages(&mut self) -> Result<()> {
+        let page_size = self.db.page_size() as u64;
+        let file = self.db.file();
+        let mut offset = 0;
+        let mut run: Vec<u8> = Vec::new();
+        let mut writes = 0;
+        let mut bytes = 0;
+        for (page_id, buf) in self.dirty.get_mut().iter() {
+            bytes += buf.len() as u64;
+            let pos = page_id * page_size;
+            if pos != offset + run.len() as u64 {
+                if !run.is_empty() {
+                    file.write_all_at(&run, offset)?;
+                    writes += 1;
+                    run.clear();
+                }
+                offset = pos;
+            }
+            run.extend_from_slice(buf);
+        }
+        if !run.is_empty() {
+            file.write_all_at(&run, offset)?;
+            writes += 1;
+        }
+        let stats = self.stats.get_mut();
+        stats.writes += writes;
+        stats.write_bytes += bytes;
+        self.db.sync()
+    }
+
+    /// Closes the transaction, discarding any changes.
+    pub fn rollback(self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Releases the transaction's hold on the database. Pages freed or
+    /// allocated by an uncommitted writable transaction are given back.
+    fn close(&mut self) {
+        self.db.add_tx_stats(self.stats.get_mut());
+        let Some(pages) = self.pages.take() else {
+            return;
+        };
+        self.nodes.get_mut().clear();
+        if self.writable() {
+            let mut freelist = self.db.freelist();
+            freelist.rollback(self.id());
+            // The freelist was read successfully when the database was
+            // opened, and the pages it was read from have not changed since.
+            let meta = self.db.meta();
+            if meta.freelist == NO_FREELIST {
+                if let Ok(ids) = db::free_pages(&pages, &meta) {
+                    freelist.reload_ids(ids);
+                }
+            } else if let Ok(page) = pages.page(meta.freelist) {
+                let _ = freelist.reload(&page);
+            }
+        } else {
+            self.db.remove_tx(self.id());
+        }
+    }
+
+    /// Returns the meta the transaction started from. A writable
+    /// transaction's own meta already accounts for the pages it allocated,
+    /// which are not written yet.
+    fn committed_meta(&self) -> Meta {
+        if self.writable() {
+            self.db.meta()
+        } else {
+            self.meta.get()
+        }
+    }
+
+    pub(crate) fn root(&self) -> Bucket<'_> {
+        Bucket::new(self, 0)
+    }
+
+    pub(crate) fn page(&self, page_id: PageId) -> Result<Page<'_>> {
+        let pages = self.pages.as_ref().ok_or(Error::TxClosed)?;
+        Ok(pages.page(page_id)?)
+    }
+
+    pub(crate) fn page_size(&self) -> usize {
+        self.db.page_size()
+    }
+
+    /// Returns the number of pages the transaction can read.
+    pub(crate) fn mapped_pages(&self) -> PageId {
+        self.pages
+            .as_ref()
+            .map_or(0, |pages| (pages.len() / self.page_size()) as PageId)
+    }
+
+    /// Returns the bytes behind `slice` with the lifetime of the transaction.
+    pub(crate) fn bytes(&self, slice: Slice) -> &[u8] {
+        unsafe { slice::from_raw_parts(slice.ptr, slice.len) }
+    }
+
+    /// Copies `bytes` into the transaction's arena.
+    pub(crate) fn alloc_bytes(&self, bytes: &[u8]) -> Slice {
+        let buf: Box<[u8]> = bytes.into();
+        let slice = Slice::new(&buf);
+        self.arena.borrow_mut().push(buf);
+        slice
+    }
+
+    /// Reserves `count` contiguous pages, from the freelist if possible and at
+    /// the end of the file otherwise, and returns the id of the first one along
+    /// with a zeroed buffer to write them with.
+    pub(crate) fn allocate(&self, count: usize) -> (PageId, Vec<u8>) {
+        let buf = vec![0u8; count * self.page_size()];
+        {
+            let mut stats = self.stats.borrow_mut();
+            stats.pages += count as u64;
+            stats.page_alloc += buf.len() as u64;
+        }
+        if let Some(page_id) = self.db.freelist().allocate(count) {
+            return (page_id, buf);
+        }
+        let mut meta = self.meta.get();
+        let page_id = meta.page_id;
+        meta.page_id += count as u64;
+        self.meta.set(meta);
+        (page_id, buf)
+    }
+
+    /// Adds the page stored at `page_id`, along with its overflow pages, to
+    /// the pages freed by this transaction.
+    pub(crate) fn free(&self, page_id: PageId) -> Result<()> {
+        let overflow = self.page(page_id)?.overflow();
+        self.db.freelist().free(self.id(), page_id, overflow)?;
+        Ok(())
+    }
+
+    pub(crate) fn add_node(&self, node: Node) -> NodeId {
+        let mut nodes = self.nodes.borrow_mut();
+        nodes.push(node);
+        nodes.len() - 1
+    }
+
+    pub(crate) fn write_page(&self, page_id: PageId, buf: Vec<u8>) {
+        self.dirty.borrow_mut().insert(page_id, buf);
+    }
+}
+
+impl Drop for Tx<'_> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}